@@ -1,12 +1,29 @@
-use soroban_sdk::{contract, contractimpl, token, Address, Env, Map, String};
+use soroban_sdk::{
+    contract, contractimpl, token, Address, Env, IntoVal, Map, String, TryFromVal, Val, Vec, I256,
+};
 
 use crate::errors::Error;
 use crate::events::{
-    emit_claimed, emit_distributed, emit_donated, emit_ownership_transferred, emit_registered,
-    emit_rules_set,
+    emit_allowance_set, emit_allowance_spent, emit_auto_distribute_set, emit_claimed,
+    emit_distributed, emit_donated, emit_donation_refunded, emit_escrow_refunded,
+    emit_escrow_released, emit_escrowed, emit_expiry_policy_set, emit_forwarded,
+    emit_keeper_bounty_set, emit_keeper_claimed, emit_min_distribution_set, emit_operator_granted,
+    emit_operator_revoked, emit_ownership_transferred, emit_payout_config_set,
+    emit_payroll_cancelled, emit_payroll_created, emit_payroll_failed, emit_payroll_paid,
+    emit_payroll_skipped, emit_preferred_asset_set, emit_push_failed, emit_push_payout_set,
+    emit_refund_window_set, emit_regifted, emit_registered, emit_round_created,
+    emit_round_finalized, emit_round_joined, emit_round_matched, emit_router_set, emit_rules_set,
+    emit_swapped, emit_unclaimed_expired, emit_vested_withdrawn, emit_vesting_created,
+    emit_vesting_revoked, emit_vesting_set,
 };
+use crate::router::SwapRouterClient;
 use crate::storage::{
-    storage_add, DataKey, DonorKey, BPS_BASE, LEDGERS_PER_YEAR, MAX_RULES, TTL_THRESHOLD,
+    storage_add, Allowance, AllowanceKey, DataKey, DistributionReport, DonorKey, EdgeKey,
+    EscrowDeposit, ExpiryPolicy, OperatorGrant, PayrollEntry, QueueEntry, RecipientShare,
+    RefundableDonation, Round, RoundDonorKey, VestingConfig, VestingSchedule, ALL_SCOPES, BPS_BASE,
+    ESCROW_TIMEOUT, LEDGERS_PER_YEAR, MAX_KEEPER_BOUNTY, MAX_PAYOUT_TARGETS, MAX_PAYROLL_ENTRIES,
+    MAX_QUEUE_BATCH, MAX_ROUND_RECIPIENTS, MAX_RULES, MAX_USER_ROUNDS, SCOPE_CLAIM,
+    SCOPE_DISTRIBUTE, SCOPE_SET_RULES, TTL_THRESHOLD,
 };
 
 #[contract]
@@ -39,6 +56,8 @@ impl CascadingDonations {
             .persistent()
             .extend_ttl(&rules_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
 
+        Self::touch_last_claim(&env, &username);
+
         emit_registered(&env, &username, &caller);
         Ok(())
     }
@@ -58,6 +77,54 @@ impl CascadingDonations {
             .persistent()
             .extend_ttl(&owner_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
 
+        // Operators were trusted by the previous owner, not the new one.
+        for operator in Self::get_operators(env.clone(), username.clone()).iter() {
+            env.storage()
+                .persistent()
+                .remove(&DataKey::Operator(username.clone(), operator.clone()));
+            emit_operator_revoked(&env, &username, &operator);
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::Operators(username.clone()));
+
+        // Payroll entries pay whoever the previous owner chose, so they go.
+        for payroll_id in Self::get_user_payroll(env.clone(), username.clone()).iter() {
+            env.storage()
+                .persistent()
+                .remove(&DataKey::Payroll(payroll_id));
+            emit_payroll_cancelled(&env, &username, payroll_id);
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::UserPayroll(username.clone()));
+
+        // The payout config, push payouts and expiry beneficiary send funds
+        // where the previous owner chose.
+        let payout_key = DataKey::PayoutConfig(username.clone());
+        if env.storage().persistent().has(&payout_key) {
+            env.storage().persistent().remove(&payout_key);
+            emit_payout_config_set(&env, &username, &Map::new(&env));
+        }
+        let push_key = DataKey::PushPayout(username.clone());
+        if env.storage().persistent().has(&push_key) {
+            env.storage().persistent().remove(&push_key);
+            emit_push_payout_set(&env, &username, false);
+        }
+        let expiry_key = DataKey::ExpiryPolicy(username.clone());
+        if env.storage().persistent().has(&expiry_key) {
+            env.storage().persistent().remove(&expiry_key);
+            emit_expiry_policy_set(&env, &username, &None);
+        }
+
+        // Allowances were set by the previous owner too; they stop working.
+        let epoch_key = DataKey::OwnerEpoch(username.clone());
+        let epoch = Self::owner_epoch(&env, &username).wrapping_add(1);
+        env.storage().persistent().set(&epoch_key, &epoch);
+        env.storage()
+            .persistent()
+            .extend_ttl(&epoch_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
         emit_ownership_transferred(&env, &username, &caller, &new_owner);
         Ok(())
     }
@@ -69,9 +136,54 @@ impl CascadingDonations {
         rules: Map<String, u32>,
     ) -> Result<(), Error> {
         caller.require_auth();
-        Self::assert_owner(&env, &caller, &username)?;
+        Self::assert_authorized(&env, &caller, &username, SCOPE_SET_RULES)?;
         Self::validate_rules(&env, &rules, &username)?;
 
+        // Carried-over shares of dependents being dropped from the rules
+        // would otherwise be stranded, so they are paid out now. Only assets
+        // that ever had a share carried over can hold any.
+        let previous = Self::get_rules(env.clone(), username.clone());
+        let owed_assets_key = DataKey::OwedAssets(username.clone());
+        let assets: Vec<Address> = env
+            .storage()
+            .persistent()
+            .get(&owed_assets_key)
+            .unwrap_or(Vec::new(&env));
+        for recipient in previous.keys().iter() {
+            if rules.contains_key(recipient.clone()) {
+                continue;
+            }
+            for asset in assets.iter() {
+                let owed_key = DataKey::Owed(EdgeKey {
+                    upstream: username.clone(),
+                    downstream: recipient.clone(),
+                    asset: asset.clone(),
+                });
+                let owed: i128 = env.storage().persistent().get(&owed_key).unwrap_or(0);
+                if owed > 0 {
+                    env.storage().persistent().remove(&owed_key);
+                    let depths = Self::take_owed_depths(&env, &username, &recipient, &asset);
+                    Self::forward(&env, &username, &recipient, &asset, owed, &depths);
+                }
+            }
+        }
+
+        // Drop assets nothing is carried over in any more.
+        let mut still_owed: Vec<Address> = Vec::new(&env);
+        for asset in assets.iter() {
+            let owed = rules.keys().iter().any(|recipient| {
+                Self::get_owed(env.clone(), username.clone(), recipient, asset.clone()) > 0
+            });
+            if owed {
+                still_owed.push_back(asset);
+            }
+        }
+        if still_owed.is_empty() {
+            env.storage().persistent().remove(&owed_assets_key);
+        } else if still_owed.len() < assets.len() {
+            env.storage().persistent().set(&owed_assets_key, &still_owed);
+        }
+
         let rules_key = DataKey::Rules(username.clone());
         env.storage().persistent().set(&rules_key, &rules);
         env.storage()
@@ -82,6 +194,15 @@ impl CascadingDonations {
         Ok(())
     }
 
+    /// `max_hops`: how many times the donation may be forwarded down the
+    /// cascade. `Some(0)` keeps it with `username`, `Some(1)` lets it reach
+    /// their direct dependencies, and so on. Funds that run out of hops stay
+    /// with the owner of the pool they are in. `None` means no limit.
+    ///
+    /// `min_out`: if the user has a preferred asset and a router, a donation
+    /// in another asset is converted before it is credited, and reverts with
+    /// `SlippageExceeded` if that yields less than `min_out`.
+    #[allow(clippy::too_many_arguments)]
     pub fn donate(
         env: Env,
         caller: Address,
@@ -89,6 +210,8 @@ impl CascadingDonations {
         asset: Address,
         amount: i128,
         donor_override: Option<Address>,
+        max_hops: Option<u32>,
+        min_out: i128,
     ) -> Result<(), Error> {
         caller.require_auth();
 
@@ -96,15 +219,19 @@ impl CascadingDonations {
             return Err(Error::InvalidAmount);
         }
 
-        if !env
-            .storage()
-            .persistent()
-            .has(&DataKey::Owner(username.clone()))
+        // Escrowed funds are pooled per donor, so they can't carry a limit.
+        if max_hops.is_some()
+            && !env
+                .storage()
+                .persistent()
+                .has(&DataKey::Owner(username.clone()))
         {
-            return Err(Error::UserNotFound);
+            return Err(Error::HopLimitNotAllowed);
         }
 
-        let donor = donor_override.unwrap_or(caller.clone());
+        // An override naming someone other than the signer is self-declared:
+        // it is tracked in the unverified counters only.
+        let attributed = donor_override.filter(|donor| *donor != caller);
 
         token::Client::new(&env, &asset).transfer(
             &caller,
@@ -112,270 +239,3207 @@ impl CascadingDonations {
             &amount,
         );
 
-        storage_add(&env, &DataKey::Pool(username.clone(), asset.clone()), amount);
-        storage_add(
-            &env,
-            &DataKey::TotalReceived(username.clone(), asset.clone()),
-            amount,
-        );
-        storage_add(
+        let (credited_asset, credited, used) =
+            Self::convert_donation(&env, &username, &caller, &asset, amount, min_out)?;
+        Self::credit_donation(
             &env,
-            &DataKey::DonorToUser(DonorKey {
-                donor: donor.clone(),
-                username: username.clone(),
-                asset: asset.clone(),
-            }),
-            amount,
-        );
-        storage_add(&env, &DataKey::DonorTotal(donor.clone(), asset.clone()), amount);
-        storage_add(&env, &DataKey::GrandTotal(asset.clone()), amount);
-
-        emit_donated(&env, &username, &donor, &asset, amount);
-        Ok(())
+            &username,
+            &caller,
+            &attributed,
+            (&asset, used),
+            &credited_asset,
+            credited,
+            max_hops,
+        )
     }
 
-    /// `min_distribution`: smallest amount worth forwarding (in token stroops).
-    /// Shares below this threshold stay with the owner instead of cascading.
-    /// Pass 0 to disable the threshold.
-    pub fn distribute(env: Env, username: String, asset: Address, min_distribution: i128) -> Result<(), Error> {
-        Self::distribute_internal(&env, &username, &asset, min_distribution)
+    /// Donates on behalf of `from` using a token allowance instead of a
+    /// direct transfer, so a relayer or payment processor can submit
+    /// donations the donor pre-approved. `spender` is the allowance holder:
+    /// either a relayer, who must sign, or this contract itself, in which
+    /// case `from` must sign. The donation is attributed to `from` and
+    /// converted into the user's preferred asset as in `donate`, with
+    /// `min_out` as the slippage bound.
+    pub fn donate_from(
+        env: Env,
+        spender: Address,
+        from: Address,
+        username: String,
+        asset: Address,
+        amount: i128,
+        min_out: i128,
+    ) -> Result<(), Error> {
+        if spender == env.current_contract_address() {
+            from.require_auth();
+        } else {
+            spender.require_auth();
+        }
+
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        token::Client::new(&env, &asset).transfer_from(
+            &spender,
+            &from,
+            &env.current_contract_address(),
+            &amount,
+        );
+
+        let (credited_asset, credited, used) =
+            Self::convert_donation(&env, &username, &from, &asset, amount, min_out)?;
+        Self::credit_donation(
+            &env,
+            &username,
+            &from,
+            &None,
+            (&asset, used),
+            &credited_asset,
+            credited,
+            None,
+        )
     }
 
-    pub fn claim(
+    /// Lets donors take back what they donated to `username` within
+    /// `ledgers` of their latest donation, as long as the pool has not been
+    /// distributed since. Pass 0 to disable refunds.
+    pub fn set_refund_window(
         env: Env,
         caller: Address,
         username: String,
-        asset: Address,
-        to: Option<Address>,
-    ) -> Result<i128, Error> {
+        ledgers: u32,
+    ) -> Result<(), Error> {
         caller.require_auth();
         Self::assert_owner(&env, &caller, &username)?;
-        Self::do_claim(&env, &caller, &username, &asset, to)
+
+        let key = DataKey::RefundWindow(username.clone());
+        env.storage().persistent().set(&key, &ledgers);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_refund_window_set(&env, &username, ledgers);
+        Ok(())
     }
 
-    pub fn distribute_and_claim(
+    /// Reverses the donations `donor` paid to `username` in `asset` since the
+    /// last distribution, including their analytics and funding round
+    /// contributions. Fails once the refund window after the latest one has
+    /// passed or the pool has been distributed.
+    pub fn refund_donation(
         env: Env,
-        caller: Address,
+        donor: Address,
         username: String,
         asset: Address,
-        to: Option<Address>,
-        min_distribution: i128,
     ) -> Result<i128, Error> {
-        caller.require_auth();
-        Self::assert_owner(&env, &caller, &username)?;
-
-        Self::distribute_internal(&env, &username, &asset, min_distribution)?;
+        donor.require_auth();
+
+        let donor_key = DonorKey {
+            donor: donor.clone(),
+            username: username.clone(),
+            asset: asset.clone(),
+        };
+        let record_key = DataKey::LastDonation(donor_key);
+        let record: RefundableDonation = env
+            .storage()
+            .persistent()
+            .get(&record_key)
+            .ok_or(Error::NothingToRefund)?;
 
-        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
-        let unclaimed: i128 = env
+        let window: u32 = env
             .storage()
             .persistent()
-            .get(&unclaimed_key)
+            .get(&DataKey::RefundWindow(username.clone()))
             .unwrap_or(0);
+        if env.ledger().sequence() > record.ledger.saturating_add(window)
+            || record.epoch != Self::pool_epoch(&env, &username, &asset)
+        {
+            return Err(Error::RefundWindowClosed);
+        }
 
-        if unclaimed == 0 {
-            return Ok(0);
+        for (round_id, amount) in record.rounds.iter() {
+            let round: Round = env
+                .storage()
+                .persistent()
+                .get(&DataKey::Round(round_id))
+                .unwrap();
+            if round.finalized {
+                return Err(Error::RefundWindowClosed);
+            }
+            Self::add_round_contribution(&env, round_id, &username, &donor, -amount);
         }
 
-        Self::do_claim(&env, &caller, &username, &asset, to)
-    }
+        env.storage().persistent().remove(&record_key);
+        storage_add(
+            &env,
+            &DataKey::Pool(username.clone(), asset.clone()),
+            -record.amount,
+        );
+        let mut depths: Map<u32, i128> = Map::new(&env);
+        for (hops, amount) in record.depths.iter() {
+            depths.set(hops, -amount);
+        }
+        Self::add_depths(
+            &env,
+            &DataKey::DepthPool(username.clone(), asset.clone()),
+            &depths,
+        );
+        storage_add(
+            &env,
+            &DataKey::TotalReceived(username.clone(), asset.clone()),
+            -record.amount,
+        );
+        let mut own = record.amount;
+        for (attributed, amount) in record.attributed.iter() {
+            Self::add_attribution(&env, &username, &donor, &Some(attributed), &asset, -amount);
+            own -= amount;
+        }
+        if own > 0 {
+            Self::add_attribution(&env, &username, &donor, &None, &asset, -own);
+        }
 
-    pub fn get_pool(env: Env, username: String, asset: Address) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::Pool(username, asset))
-            .unwrap_or(0)
-    }
+        token::Client::new(&env, &asset).transfer(
+            &env.current_contract_address(),
+            &donor,
+            &record.amount,
+        );
 
-    pub fn get_rules(env: Env, username: String) -> Map<String, u32> {
-        env.storage()
-            .persistent()
-            .get(&DataKey::Rules(username))
-            .unwrap_or(Map::new(&env))
+        emit_donation_refunded(&env, &username, &donor, &asset, record.amount);
+        Ok(record.amount)
     }
 
-    pub fn get_owner(env: Env, username: String) -> Option<Address> {
-        env.storage()
+    /// Permissionless once `username` is registered. Moves everything
+    /// escrowed for it in `asset` into its pool, one asset per call so that
+    /// no number of escrowed assets can make registering or releasing
+    /// unaffordable. Per-donor deposits are left behind; they are ignored
+    /// once the username has an owner. Returns the amount released.
+    pub fn release_escrow(env: Env, username: String, asset: Address) -> Result<i128, Error> {
+        if !env
+            .storage()
             .persistent()
-            .get(&DataKey::Owner(username))
-    }
+            .has(&DataKey::Owner(username.clone()))
+        {
+            return Err(Error::UserNotFound);
+        }
 
-    pub fn get_total_received(env: Env, username: String, asset: Address) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::TotalReceived(username, asset))
-            .unwrap_or(0)
+        let total_key = DataKey::EscrowTotal(username.clone(), asset.clone());
+        let total: i128 = env.storage().persistent().get(&total_key).unwrap_or(0);
+        if total == 0 {
+            return Err(Error::NothingToRefund);
+        }
+        env.storage().persistent().remove(&total_key);
+
+        Self::credit_pool(&env, &username, &asset, total);
+        storage_add(
+            &env,
+            &DataKey::TotalReceived(username.clone(), asset.clone()),
+            total,
+        );
+
+        emit_escrow_released(&env, &username, &asset, total);
+        Ok(total)
     }
 
-    pub fn get_total_received_from_others(
+    /// Takes back funds escrowed for a username that was never registered.
+    /// Only possible once `ESCROW_TIMEOUT` ledgers have passed since the
+    /// donor's latest escrowed donation. `donor` is the address that paid,
+    /// whoever the donation was attributed to. Returns the amount refunded.
+    pub fn refund_escrow(
         env: Env,
+        donor: Address,
         username: String,
         asset: Address,
-    ) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::TotalReceivedFromOthers(username, asset))
-            .unwrap_or(0)
-    }
+    ) -> Result<i128, Error> {
+        donor.require_auth();
 
-    pub fn get_total_forwarded(env: Env, username: String, asset: Address) -> i128 {
-        env.storage()
+        if env
+            .storage()
             .persistent()
-            .get(&DataKey::TotalForwarded(username, asset))
-            .unwrap_or(0)
-    }
+            .has(&DataKey::Owner(username.clone()))
+        {
+            return Err(Error::NothingToRefund);
+        }
 
-    pub fn get_unclaimed(env: Env, username: String, asset: Address) -> i128 {
-        env.storage()
+        let donor_key = DonorKey {
+            donor: donor.clone(),
+            username: username.clone(),
+            asset: asset.clone(),
+        };
+        let escrow_key = DataKey::Escrow(donor_key);
+        let deposit: EscrowDeposit = env
+            .storage()
             .persistent()
-            .get(&DataKey::Unclaimed(username, asset))
-            .unwrap_or(0)
+            .get(&escrow_key)
+            .ok_or(Error::NothingToRefund)?;
+
+        if env.ledger().sequence() < deposit.ledger + ESCROW_TIMEOUT {
+            return Err(Error::EscrowLocked);
+        }
+
+        env.storage().persistent().remove(&escrow_key);
+        let total_key = DataKey::EscrowTotal(username.clone(), asset.clone());
+        storage_add(&env, &total_key, -deposit.amount);
+        Self::add_attribution(
+            &env,
+            &username,
+            &donor,
+            &deposit.attributed,
+            &asset,
+            -deposit.amount,
+        );
+
+        token::Client::new(&env, &asset).transfer(
+            &env.current_contract_address(),
+            &donor,
+            &deposit.amount,
+        );
+
+        emit_escrow_refunded(&env, &username, &donor, &asset, deposit.amount);
+        Ok(deposit.amount)
     }
 
-    pub fn get_donor_to_user(
+    /// `min_distribution`: smallest amount worth forwarding (in token stroops).
+    /// Shares below this threshold stay with the owner instead of cascading.
+    /// A value above the owner's stored threshold for the asset (see
+    /// `set_min_distribution`) is lowered to it, so callers cannot starve
+    /// dependents by passing a huge value. Pass 0 to disable the threshold.
+    /// The report and the `distributed` event carry the value applied.
+    ///
+    /// Returns who got what: each recipient's share and whether it was
+    /// skipped, the owner's remainder and the total forwarded.
+    pub fn distribute(
         env: Env,
-        donor: Address,
         username: String,
         asset: Address,
-    ) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::DonorToUser(DonorKey {
-                donor,
-                username,
-                asset,
-            }))
-            .unwrap_or(0)
-    }
-
-    pub fn get_donor_total(env: Env, donor: Address, asset: Address) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::DonorTotal(donor, asset))
-            .unwrap_or(0)
-    }
-
-    pub fn get_grand_total(env: Env, asset: Address) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::GrandTotal(asset))
-            .unwrap_or(0)
+        min_distribution: i128,
+    ) -> Result<DistributionReport, Error> {
+        Self::distribute_internal(&env, &username, &asset, None, min_distribution, None)
     }
 
-    pub fn get_paid_to(env: Env, address: Address, asset: Address) -> i128 {
-        env.storage()
-            .persistent()
-            .get(&DataKey::PaidTo(address, asset))
-            .unwrap_or(0)
+    /// Same as `distribute`, crediting `keeper` with the user's keeper
+    /// bounty, if any, before the pool is split.
+    pub fn distribute_with_keeper(
+        env: Env,
+        username: String,
+        asset: Address,
+        min_distribution: i128,
+        keeper: Address,
+    ) -> Result<DistributionReport, Error> {
+        Self::distribute_internal(&env, &username, &asset, None, min_distribution, Some(&keeper))
     }
 
-    fn distribute_internal(
-        env: &Env,
-        username: &String,
-        asset: &Address,
+    /// Permissionless. Pops up to `max_items` pools (at most
+    /// `MAX_QUEUE_BATCH`) off the distribution queue and distributes each
+    /// one. `min_distribution` is lowered to each owner's stored threshold
+    /// where it exceeds it, and pools that were emptied since they were
+    /// queued or entries that are gone from storage are skipped, so one entry
+    /// never fails the batch. A pool whose distribution fails is queued
+    /// again at the back. Downstream pools that receive funds are queued in
+    /// turn. Returns the number of pools distributed.
+    pub fn process_queue(
+        env: Env,
+        max_items: u32,
         min_distribution: i128,
-    ) -> Result<(), Error> {
-        if !env
+        keeper: Option<Address>,
+    ) -> u32 {
+        let mut head: u32 = env
             .storage()
             .persistent()
-            .has(&DataKey::Owner(username.clone()))
-        {
-            return Err(Error::UserNotFound);
-        }
-
-        let rules: Map<String, u32> = env
+            .get(&DataKey::QueueHead)
+            .unwrap_or(0);
+        let tail: u32 = env
             .storage()
             .persistent()
-            .get(&DataKey::Rules(username.clone()))
-            .ok_or(Error::RulesNotSet)?;
-
-        let pool_key = DataKey::Pool(username.clone(), asset.clone());
-        let pool: i128 = env.storage().persistent().get(&pool_key).unwrap_or(0);
-
-        if pool == 0 {
-            return Err(Error::NothingToDistribute);
-        }
-
-        let mut total_shared: i128 = 0;
-        let keys = rules.keys();
+            .get(&DataKey::QueueTail)
+            .unwrap_or(0);
 
-        for i in 0..keys.len() {
-            let recipient = keys.get(i).unwrap();
-            let pct = rules.get(recipient.clone()).unwrap() as i128;
-            let share = pool * pct / (BPS_BASE as i128);
+        let mut processed: u32 = 0;
+        let mut distributed: u32 = 0;
+        while head < tail && processed < max_items.min(MAX_QUEUE_BATCH) {
+            let item_key = DataKey::QueueItem(head);
+            let entry: Option<QueueEntry> = env.storage().persistent().get(&item_key);
+            head += 1;
+            processed += 1;
+            // An archived entry can't be read back; its pool is queued
+            // again the next time it is credited or distributed.
+            let Some(entry) = entry else {
+                continue;
+            };
+            env.storage().persistent().remove(&item_key);
+            env.storage()
+                .persistent()
+                .remove(&DataKey::Queued(entry.username.clone(), entry.asset.clone()));
 
-            if share < min_distribution {
+            if Self::get_pool(env.clone(), entry.username.clone(), entry.asset.clone()) == 0 {
                 continue;
             }
-            total_shared += share;
-
-            storage_add(env, &DataKey::Pool(recipient.clone(), asset.clone()), share);
-            storage_add(
-                env,
-                &DataKey::TotalReceived(recipient.clone(), asset.clone()),
-                share,
-            );
-            storage_add(
-                env,
-                &DataKey::TotalReceivedFromOthers(recipient.clone(), asset.clone()),
-                share,
-            );
-        }
-
-        if total_shared > 0 {
-            storage_add(
-                env,
-                &DataKey::TotalForwarded(username.clone(), asset.clone()),
-                total_shared,
-            );
+            if Self::distribute_internal(
+                &env,
+                &entry.username,
+                &entry.asset,
+                None,
+                min_distribution,
+                keeper.as_ref(),
+            )
+            .is_ok()
+            {
+                distributed += 1;
+            } else {
+                Self::enqueue(&env, &entry.username, &entry.asset);
+            }
         }
 
-        let owner_share = pool - total_shared;
-        if owner_share > 0 {
+        env.storage().persistent().set(&DataKey::QueueHead, &head);
+        env.storage()
+            .persistent()
+            .extend_ttl(&DataKey::QueueHead, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+        distributed
+    }
+
+    /// Read-only preview of `distribute(username, asset, min_distribution)`,
+    /// or of `distribute_with_keeper` if `keeper` is set: each recipient's
+    /// share, what would be carried over instead of forwarded, the keeper
+    /// reward, the owner's remainder and the pool snapshot. Fails with the
+    /// same errors the distribution would.
+    pub fn quote_distribution(
+        env: Env,
+        username: String,
+        asset: Address,
+        min_distribution: i128,
+        keeper: Option<Address>,
+    ) -> Result<DistributionReport, Error> {
+        Self::plan_distribution(&env, &username, &asset, None, min_distribution, keeper.is_some())
+    }
+
+    /// Distributes only `amount` of the pool, leaving the rest in place.
+    /// Lets the owner smooth payouts to dependents over time, or move a
+    /// large pool in chunks. Same `min_distribution` rules as `distribute`,
+    /// except that operators always use the owner's stored threshold.
+    pub fn distribute_amount(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Address,
+        amount: i128,
+        min_distribution: i128,
+    ) -> Result<DistributionReport, Error> {
+        caller.require_auth();
+        let is_owner = Self::assert_authorized(&env, &caller, &username, SCOPE_DISTRIBUTE)?;
+        let min_distribution = if is_owner {
+            min_distribution
+        } else {
+            Self::min_distribution(&env, &username, &asset)
+        };
+        Self::distribute_internal(&env, &username, &asset, Some(amount), min_distribution, None)
+    }
+
+    pub fn claim(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Address,
+        to: Option<Address>,
+    ) -> Result<i128, Error> {
+        caller.require_auth();
+        let fallback = Self::authorize_claim(&env, &caller, &username, &to)?;
+        Self::do_claim(&env, &fallback, &username, &asset, to)
+    }
+
+    /// Claims the unclaimed balance of `asset` converted into `target_asset`
+    /// through the user's router (see `set_router`). Reverts with
+    /// `SlippageExceeded` if the conversion yields less than `min_out`.
+    /// Payees are chosen as in `claim`. Returns the amount of
+    /// `target_asset` paid out.
+    ///
+    /// Owner-only, even for operators holding `SCOPE_CLAIM`: whoever picks
+    /// `target_asset` and `min_out` can give the balance away in the swap.
+    pub fn claim_as(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Address,
+        target_asset: Address,
+        min_out: i128,
+        to: Option<Address>,
+    ) -> Result<i128, Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+        let fallback = caller;
+
+        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
+        let unclaimed: i128 = env
+            .storage()
+            .persistent()
+            .get(&unclaimed_key)
+            .unwrap_or(0);
+
+        if unclaimed == 0 {
+            return Err(Error::NothingToDistribute);
+        }
+        let router: Address = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Router(username.clone()))
+            .ok_or(Error::RouterNotSet)?;
+
+        env.storage().persistent().set(&unclaimed_key, &0i128);
+        let (spent, out) = Self::swap(&env, &router, &asset, &target_asset, unclaimed, min_out)?;
+        emit_swapped(&env, &username, &asset, spent, &target_asset, out);
+
+        // Whatever the router left unspent stays claimable.
+        if spent < unclaimed {
+            storage_add(&env, &unclaimed_key, unclaimed - spent);
+        }
+
+        let payees = match to {
+            Some(to) => Map::from_array(&env, [(to, out)]),
+            None => Self::split_payout(&env, &username, &fallback, out),
+        };
+        Self::pay_out(&env, &username, &target_asset, &payees);
+        Ok(out)
+    }
+
+    /// Withdraws whatever has vested on a schedule and not been withdrawn
+    /// yet. Only the schedule's beneficiary can call this. Returns the
+    /// amount transferred.
+    pub fn withdraw_vested(env: Env, schedule_id: u32) -> Result<i128, Error> {
+        let key = DataKey::Vesting(schedule_id);
+        let mut schedule: VestingSchedule = env
+            .storage()
+            .persistent()
+            .get(&key)
+            .ok_or(Error::ScheduleNotFound)?;
+        schedule.beneficiary.require_auth();
+
+        let amount = Self::vested(&env, &schedule) - schedule.withdrawn;
+        if amount <= 0 {
+            return Err(Error::NothingToDistribute);
+        }
+
+        token::Client::new(&env, &schedule.asset).transfer(
+            &env.current_contract_address(),
+            &schedule.beneficiary,
+            &amount,
+        );
+        storage_add(
+            &env,
+            &DataKey::PaidTo(schedule.beneficiary.clone(), schedule.asset.clone()),
+            amount,
+        );
+
+        schedule.withdrawn += amount;
+        Self::save_vesting(&env, schedule_id, &schedule);
+
+        emit_vested_withdrawn(&env, schedule_id, &schedule.beneficiary, &schedule.asset, amount);
+        Ok(amount)
+    }
+
+    /// Stops a schedule from vesting further. What has vested stays
+    /// withdrawable by the beneficiary; the unvested rest goes back to the
+    /// user's unclaimed balance. Only the owner can call this. Returns the
+    /// amount taken back.
+    pub fn revoke_vesting(env: Env, caller: Address, schedule_id: u32) -> Result<i128, Error> {
+        caller.require_auth();
+
+        let mut schedule: VestingSchedule = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Vesting(schedule_id))
+            .ok_or(Error::ScheduleNotFound)?;
+        Self::assert_owner(&env, &caller, &schedule.username)?;
+
+        let unvested = schedule.total - Self::vested(&env, &schedule);
+        if unvested <= 0 {
+            return Err(Error::NothingToRefund);
+        }
+
+        // Freeze the schedule at what has vested so far.
+        schedule.total -= unvested;
+        schedule.duration = env.ledger().sequence() - schedule.start_ledger;
+        storage_add(
+            &env,
+            &DataKey::Unclaimed(schedule.username.clone(), schedule.asset.clone()),
+            unvested,
+        );
+        Self::save_vesting(&env, schedule_id, &schedule);
+
+        emit_vesting_revoked(&env, &schedule.username, schedule_id, unvested);
+        Ok(unvested)
+    }
+
+    /// Distributes the pool, then claims the owner's balance of `asset` as
+    /// `claim` would. Operators need both `SCOPE_CLAIM` and
+    /// `SCOPE_DISTRIBUTE`, and run the distribution with the owner's stored
+    /// threshold. Returns the distribution report and the amount claimed.
+    pub fn distribute_and_claim(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Address,
+        to: Option<Address>,
+        min_distribution: i128,
+    ) -> Result<(DistributionReport, i128), Error> {
+        caller.require_auth();
+        let fallback = Self::authorize_claim(&env, &caller, &username, &to)?;
+        let is_owner = Self::assert_authorized(&env, &caller, &username, SCOPE_DISTRIBUTE)?;
+        let min_distribution = if is_owner {
+            min_distribution
+        } else {
+            Self::min_distribution(&env, &username, &asset)
+        };
+
+        let report =
+            Self::distribute_internal(&env, &username, &asset, None, min_distribution, None)?;
+
+        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
+        let unclaimed: i128 = env
+            .storage()
+            .persistent()
+            .get(&unclaimed_key)
+            .unwrap_or(0);
+
+        if unclaimed == 0 {
+            return Ok((report, 0));
+        }
+
+        let claimed = Self::do_claim(&env, &fallback, &username, &asset, to)?;
+        Ok((report, claimed))
+    }
+
+    /// Moves `amount` of the owner's unclaimed balance straight into
+    /// `target_username`'s pool, as a donation from `username`, without
+    /// any token transfer. Only the owner can call this.
+    pub fn regift(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Address,
+        amount: i128,
+        target_username: String,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        if target_username == username {
+            return Err(Error::SelfReference);
+        }
+        if !env
+            .storage()
+            .persistent()
+            .has(&DataKey::Owner(target_username.clone()))
+        {
+            return Err(Error::RecipientNotRegistered);
+        }
+        let unclaimed = Self::get_unclaimed(env.clone(), username.clone(), asset.clone());
+        if amount <= 0 || amount > unclaimed {
+            return Err(Error::InvalidAmount);
+        }
+
+        storage_add(
+            &env,
+            &DataKey::Unclaimed(username.clone(), asset.clone()),
+            -amount,
+        );
+        Self::credit_pool(&env, &target_username, &asset, amount);
+        storage_add(
+            &env,
+            &DataKey::TotalReceived(target_username.clone(), asset.clone()),
+            amount,
+        );
+        storage_add(
+            &env,
+            &DataKey::Regifted(EdgeKey {
+                upstream: username.clone(),
+                downstream: target_username.clone(),
+                asset: asset.clone(),
+            }),
+            amount,
+        );
+        storage_add(
+            &env,
+            &DataKey::TotalRegifted(username.clone(), asset.clone()),
+            amount,
+        );
+
+        emit_regifted(&env, &username, &target_username, &asset, amount);
+
+        if Self::get_auto_distribute(env.clone(), target_username.clone()) {
+            let min_distribution = Self::min_distribution(&env, &target_username, &asset);
+            Self::distribute_internal(
+                &env,
+                &target_username,
+                &asset,
+                None,
+                min_distribution,
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Lets `spender` withdraw up to `amount` of `asset` from the user's
+    /// unclaimed balance, once or, with `period`, every `period` ledgers.
+    /// Replaces any previous allowance; pass an `amount` of 0 to remove it.
+    /// Only the owner can call this.
+    pub fn set_allowance(
+        env: Env,
+        caller: Address,
+        username: String,
+        spender: Address,
+        asset: Address,
+        amount: i128,
+        period: Option<u32>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        if amount < 0 || period == Some(0) {
+            return Err(Error::InvalidAmount);
+        }
+
+        let key = DataKey::Allowance(AllowanceKey {
+            username: username.clone(),
+            spender: spender.clone(),
+            asset: asset.clone(),
+        });
+        if amount == 0 {
+            env.storage().persistent().remove(&key);
+        } else {
+            let allowance = Allowance {
+                amount,
+                spent: 0,
+                period,
+                period_start: env.ledger().sequence(),
+                epoch: Self::owner_epoch(&env, &username),
+            };
+            env.storage().persistent().set(&key, &allowance);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+        }
+
+        emit_allowance_set(&env, &username, &spender, &asset, amount, period);
+        Ok(())
+    }
+
+    /// Withdraws `amount` from the user's unclaimed balance against the
+    /// caller's allowance. `to` defaults to the spender. Returns the
+    /// allowance left in the current period.
+    pub fn spend_allowance(
+        env: Env,
+        spender: Address,
+        username: String,
+        asset: Address,
+        amount: i128,
+        to: Option<Address>,
+    ) -> Result<i128, Error> {
+        spender.require_auth();
+
+        let key = DataKey::Allowance(AllowanceKey {
+            username: username.clone(),
+            spender: spender.clone(),
+            asset: asset.clone(),
+        });
+        let mut allowance: Allowance = env
+            .storage()
+            .persistent()
+            .get(&key)
+            .filter(|allowance: &Allowance| allowance.epoch == Self::owner_epoch(&env, &username))
+            .ok_or(Error::NotAuthorized)?;
+        Self::roll_allowance(&env, &mut allowance);
+
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if amount > allowance.amount - allowance.spent {
+            return Err(Error::AllowanceExceeded);
+        }
+        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
+        let unclaimed: i128 = env
+            .storage()
+            .persistent()
+            .get(&unclaimed_key)
+            .unwrap_or(0);
+        if amount > unclaimed {
+            return Err(Error::InvalidAmount);
+        }
+
+        allowance.spent += amount;
+        env.storage().persistent().set(&key, &allowance);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+        env.storage()
+            .persistent()
+            .set(&unclaimed_key, &(unclaimed - amount));
+
+        let recipient = to.unwrap_or(spender.clone());
+        token::Client::new(&env, &asset).transfer(
+            &env.current_contract_address(),
+            &recipient,
+            &amount,
+        );
+        storage_add(&env, &DataKey::PaidTo(recipient.clone(), asset.clone()), amount);
+        Self::touch_last_claim(&env, &username);
+
+        emit_allowance_spent(&env, &username, &spender, &recipient, &asset, amount);
+        Ok(allowance.amount - allowance.spent)
+    }
+
+    /// Schedules `amount` of `asset` to be paid to `recipient` from the
+    /// user's unclaimed balance every `interval` ledgers, starting at
+    /// `start_ledger` (default: now). Only the owner can call this. Returns
+    /// the payroll id.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_payroll(
+        env: Env,
+        caller: Address,
+        username: String,
+        recipient: Address,
+        asset: Address,
+        amount: i128,
+        interval: u32,
+        start_ledger: Option<u32>,
+    ) -> Result<u32, Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        if amount <= 0 || interval == 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let index_key = DataKey::UserPayroll(username.clone());
+        let mut ids = Self::get_user_payroll(env.clone(), username.clone());
+        if ids.len() >= MAX_PAYROLL_ENTRIES {
+            return Err(Error::TooManyPayrollEntries);
+        }
+
+        let payroll_id: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::PayrollCount)
+            .unwrap_or(0);
+        env.storage()
+            .persistent()
+            .set(&DataKey::PayrollCount, &(payroll_id + 1));
+        env.storage()
+            .persistent()
+            .extend_ttl(&DataKey::PayrollCount, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        let entry = PayrollEntry {
+            username: username.clone(),
+            recipient,
+            asset,
+            amount,
+            interval,
+            next_ledger: start_ledger.unwrap_or(env.ledger().sequence()),
+        };
+        Self::save_payroll(&env, payroll_id, &entry);
+
+        ids.push_back(payroll_id);
+        env.storage().persistent().set(&index_key, &ids);
+        env.storage()
+            .persistent()
+            .extend_ttl(&index_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_payroll_created(&env, &username, payroll_id, &entry);
+        Ok(payroll_id)
+    }
+
+    /// Deletes a payroll entry. Only the owner can call this.
+    pub fn cancel_payroll(env: Env, caller: Address, payroll_id: u32) -> Result<(), Error> {
+        caller.require_auth();
+
+        let key = DataKey::Payroll(payroll_id);
+        let entry: PayrollEntry = env
+            .storage()
+            .persistent()
+            .get(&key)
+            .ok_or(Error::PayrollNotFound)?;
+        Self::assert_owner(&env, &caller, &entry.username)?;
+
+        env.storage().persistent().remove(&key);
+        let index_key = DataKey::UserPayroll(entry.username.clone());
+        let mut ids = Self::get_user_payroll(env.clone(), entry.username.clone());
+        if let Some(i) = ids.first_index_of(payroll_id) {
+            ids.remove(i);
+        }
+        if ids.is_empty() {
+            env.storage().persistent().remove(&index_key);
+        } else {
+            env.storage().persistent().set(&index_key, &ids);
+        }
+
+        emit_payroll_cancelled(&env, &entry.username, payroll_id);
+        Ok(())
+    }
+
+    /// Permissionless. Pays every payroll entry of `username` that is due,
+    /// once each, and moves it to its first due ledger after now: periods
+    /// missed while nobody ran the payroll are not paid retroactively. An
+    /// entry the unclaimed balance can't cover is skipped with a
+    /// `payroll_skipped` event, and one whose transfer is rejected with a
+    /// `payroll_failed` event; both stay due. Returns the number of entries
+    /// paid.
+    pub fn execute_payroll(env: Env, username: String) -> u32 {
+        let now = env.ledger().sequence();
+        let mut paid: u32 = 0;
+
+        for payroll_id in Self::get_user_payroll(env.clone(), username.clone()).iter() {
+            let entry: Option<PayrollEntry> = env
+                .storage()
+                .persistent()
+                .get(&DataKey::Payroll(payroll_id));
+            let Some(mut entry) = entry else {
+                continue;
+            };
+            if entry.next_ledger > now {
+                continue;
+            }
+
+            let unclaimed_key = DataKey::Unclaimed(username.clone(), entry.asset.clone());
+            let unclaimed: i128 = env
+                .storage()
+                .persistent()
+                .get(&unclaimed_key)
+                .unwrap_or(0);
+            if unclaimed < entry.amount {
+                emit_payroll_skipped(&env, &username, payroll_id, &entry, unclaimed);
+                continue;
+            }
+
+            let sent = token::Client::new(&env, &entry.asset).try_transfer(
+                &env.current_contract_address(),
+                &entry.recipient,
+                &entry.amount,
+            );
+            if !matches!(sent, Ok(Ok(()))) {
+                emit_payroll_failed(&env, &username, payroll_id, &entry);
+                continue;
+            }
+            env.storage()
+                .persistent()
+                .set(&unclaimed_key, &(unclaimed - entry.amount));
             storage_add(
+                &env,
+                &DataKey::PaidTo(entry.recipient.clone(), entry.asset.clone()),
+                entry.amount,
+            );
+
+            let periods = (now - entry.next_ledger) / entry.interval + 1;
+            entry.next_ledger = entry
+                .next_ledger
+                .saturating_add(periods.saturating_mul(entry.interval));
+            Self::save_payroll(&env, payroll_id, &entry);
+
+            emit_payroll_paid(&env, &username, payroll_id, &entry);
+            paid += 1;
+        }
+        if paid > 0 {
+            Self::touch_last_claim(&env, &username);
+        }
+        paid
+    }
+
+    /// Sets what happens to unclaimed balances once nothing has been claimed
+    /// for `policy.after_ledgers`, so funds don't stay stuck if the owner
+    /// loses their key. Pass `None` to keep unclaimed balances forever.
+    /// Only the owner can call this.
+    pub fn set_expiry_policy(
+        env: Env,
+        caller: Address,
+        username: String,
+        policy: Option<ExpiryPolicy>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::ExpiryPolicy(username.clone());
+        match &policy {
+            Some(expiry) => {
+                if expiry.after_ledgers == 0 {
+                    return Err(Error::InvalidAmount);
+                }
+                env.storage().persistent().set(&key, expiry);
+                env.storage()
+                    .persistent()
+                    .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+            }
+            None => env.storage().persistent().remove(&key),
+        }
+
+        emit_expiry_policy_set(&env, &username, &policy);
+        Ok(())
+    }
+
+    /// Permissionless. Once the user's expiry policy applies, sends the
+    /// whole unclaimed balance of `asset` to the policy's beneficiary, or
+    /// back into the pool to cascade to dependents. Returns the amount.
+    pub fn expire_unclaimed(env: Env, username: String, asset: Address) -> Result<i128, Error> {
+        let policy: ExpiryPolicy = env
+            .storage()
+            .persistent()
+            .get(&DataKey::ExpiryPolicy(username.clone()))
+            .ok_or(Error::NoExpiryPolicy)?;
+
+        let last_claim = Self::get_last_claim(env.clone(), username.clone());
+        if env.ledger().sequence() <= last_claim.saturating_add(policy.after_ledgers) {
+            return Err(Error::NotExpired);
+        }
+
+        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
+        let unclaimed: i128 = env
+            .storage()
+            .persistent()
+            .get(&unclaimed_key)
+            .unwrap_or(0);
+        if unclaimed == 0 {
+            return Err(Error::NothingToDistribute);
+        }
+        env.storage().persistent().set(&unclaimed_key, &0i128);
+
+        match &policy.beneficiary {
+            Some(beneficiary) => {
+                token::Client::new(&env, &asset).transfer(
+                    &env.current_contract_address(),
+                    beneficiary,
+                    &unclaimed,
+                );
+                storage_add(
+                    &env,
+                    &DataKey::PaidTo(beneficiary.clone(), asset.clone()),
+                    unclaimed,
+                );
+            }
+            None => Self::credit_pool(&env, &username, &asset, unclaimed),
+        }
+
+        emit_unclaimed_expired(&env, &username, &asset, unclaimed, &policy.beneficiary);
+        Ok(unclaimed)
+    }
+
+    /// Distributes each of `assets`, skipping assets whose pool is empty.
+    /// The caller picks the assets (see `get_assets`), so tokens nobody
+    /// cares about can't make the call more expensive. Returns the number
+    /// of pools distributed.
+    pub fn distribute_all(
+        env: Env,
+        username: String,
+        assets: Vec<Address>,
+        min_distribution: i128,
+        keeper: Option<Address>,
+    ) -> Result<u32, Error> {
+        if !env
+            .storage()
+            .persistent()
+            .has(&DataKey::Owner(username.clone()))
+        {
+            return Err(Error::UserNotFound);
+        }
+
+        let mut distributed: u32 = 0;
+        for asset in assets.iter() {
+            if Self::get_pool(env.clone(), username.clone(), asset.clone()) == 0 {
+                continue;
+            }
+            Self::distribute_internal(
+                &env,
+                &username,
+                &asset,
+                None,
+                min_distribution,
+                keeper.as_ref(),
+            )?;
+            distributed += 1;
+        }
+        Ok(distributed)
+    }
+
+    /// Claims the unclaimed balance of each of `assets`, skipping empty
+    /// ones. Leaving a token out keeps it from failing the whole call.
+    /// Returns the amount claimed per asset.
+    pub fn claim_all(
+        env: Env,
+        caller: Address,
+        username: String,
+        assets: Vec<Address>,
+        to: Option<Address>,
+    ) -> Result<Map<Address, i128>, Error> {
+        caller.require_auth();
+        let fallback = Self::authorize_claim(&env, &caller, &username, &to)?;
+
+        let mut claimed: Map<Address, i128> = Map::new(&env);
+        for asset in assets.iter() {
+            if Self::get_unclaimed(env.clone(), username.clone(), asset.clone()) == 0 {
+                continue;
+            }
+            let amount = Self::do_claim(&env, &fallback, &username, &asset, to.clone())?;
+            claimed.set(asset, amount);
+        }
+        Ok(claimed)
+    }
+
+    /// Stores the owner's dust threshold for `asset`: the smallest share, in
+    /// token stroops, worth forwarding downstream. Distributions may apply
+    /// this threshold or a lower one, never a higher one.
+    pub fn set_min_distribution(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Address,
+        min_distribution: i128,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        if min_distribution < 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let key = DataKey::MinDistribution(username.clone(), asset.clone());
+        env.storage().persistent().set(&key, &min_distribution);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_min_distribution_set(&env, &username, &asset, min_distribution);
+        Ok(())
+    }
+
+    /// When enabled, every donation to `username` is distributed one hop
+    /// immediately, using the owner's stored `min_distribution`, so funds
+    /// never sit in the pool.
+    pub fn set_auto_distribute(
+        env: Env,
+        caller: Address,
+        username: String,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::AutoDistribute(username.clone());
+        env.storage().persistent().set(&key, &enabled);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_auto_distribute_set(&env, &username, enabled);
+        Ok(())
+    }
+
+    /// Lets `operator` act for the owner within `scopes` (a bitmask of
+    /// `SCOPE_CLAIM`, `SCOPE_SET_RULES` and `SCOPE_DISTRIBUTE`) until
+    /// `expires_ledger`, if set. Granting again replaces the previous grant.
+    pub fn grant_operator(
+        env: Env,
+        caller: Address,
+        username: String,
+        operator: Address,
+        scopes: u32,
+        expires_ledger: Option<u32>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        if scopes == 0 || scopes & !ALL_SCOPES != 0 || operator == caller {
+            return Err(Error::InvalidScope);
+        }
+
+        let grant = OperatorGrant {
+            scopes,
+            expires_ledger,
+        };
+        let key = DataKey::Operator(username.clone(), operator.clone());
+        env.storage().persistent().set(&key, &grant);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        let index_key = DataKey::Operators(username.clone());
+        let mut operators = Self::get_operators(env.clone(), username.clone());
+        if !operators.contains(&operator) {
+            operators.push_back(operator.clone());
+            env.storage().persistent().set(&index_key, &operators);
+        }
+        env.storage()
+            .persistent()
+            .extend_ttl(&index_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_operator_granted(&env, &username, &operator, &grant);
+        Ok(())
+    }
+
+    pub fn revoke_operator(
+        env: Env,
+        caller: Address,
+        username: String,
+        operator: Address,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::Operator(username.clone(), operator.clone());
+        if !env.storage().persistent().has(&key) {
+            return Err(Error::NotAuthorized);
+        }
+        env.storage().persistent().remove(&key);
+
+        let index_key = DataKey::Operators(username.clone());
+        let mut operators = Self::get_operators(env.clone(), username.clone());
+        if let Some(i) = operators.first_index_of(&operator) {
+            operators.remove(i);
+        }
+        if operators.is_empty() {
+            env.storage().persistent().remove(&index_key);
+        } else {
+            env.storage().persistent().set(&index_key, &operators);
+        }
+
+        emit_operator_revoked(&env, &username, &operator);
+        Ok(())
+    }
+
+    /// Makes claims vest instead of paying out at once: each payee gets a
+    /// schedule with `config`'s cliff and duration, counted in ledgers from
+    /// the claim. Pass `None` to pay claims out directly again; existing
+    /// schedules are unaffected.
+    pub fn set_vesting(
+        env: Env,
+        caller: Address,
+        username: String,
+        config: Option<VestingConfig>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::VestingConfig(username.clone());
+        match &config {
+            Some(vesting) => {
+                if vesting.duration == 0 || vesting.cliff > vesting.duration {
+                    return Err(Error::InvalidVesting);
+                }
+                env.storage().persistent().set(&key, vesting);
+                env.storage()
+                    .persistent()
+                    .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+            }
+            None => env.storage().persistent().remove(&key),
+        }
+
+        emit_vesting_set(&env, &username, &config);
+        Ok(())
+    }
+
+    /// Sets the asset the user wants to receive. Donations in any other
+    /// asset are converted through the user's router before being credited.
+    /// Pass `None` to accept every asset as is.
+    pub fn set_preferred_asset(
+        env: Env,
+        caller: Address,
+        username: String,
+        asset: Option<Address>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::PreferredAsset(username.clone());
+        match &asset {
+            Some(asset) => {
+                env.storage().persistent().set(&key, asset);
+                env.storage()
+                    .persistent()
+                    .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+            }
+            None => env.storage().persistent().remove(&key),
+        }
+
+        emit_preferred_asset_set(&env, &username, &asset);
+        Ok(())
+    }
+
+    /// Sets the AMM router used to convert the user's funds (see
+    /// `SwapRouter`). Pass `None` to remove it.
+    pub fn set_router(
+        env: Env,
+        caller: Address,
+        username: String,
+        router: Option<Address>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::Router(username.clone());
+        match &router {
+            Some(router) => {
+                env.storage().persistent().set(&key, router);
+                env.storage()
+                    .persistent()
+                    .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+            }
+            None => env.storage().persistent().remove(&key),
+        }
+
+        emit_router_set(&env, &username, &router);
+        Ok(())
+    }
+
+    /// When enabled, each distribution sends the owner's share straight to
+    /// the payout addresses (or the owner) instead of the unclaimed balance.
+    /// A transfer that fails leaves that part in the unclaimed balance.
+    pub fn set_push_payout(
+        env: Env,
+        caller: Address,
+        username: String,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::PushPayout(username.clone());
+        env.storage().persistent().set(&key, &enabled);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_push_payout_set(&env, &username, enabled);
+        Ok(())
+    }
+
+    /// Stores where the owner's share goes when it is claimed without an
+    /// explicit `to`: a map of payout addresses to BPS summing to 10 000.
+    /// Pass an empty map to go back to paying the claiming owner.
+    pub fn set_payout_config(
+        env: Env,
+        caller: Address,
+        username: String,
+        targets: Map<Address, u32>,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let key = DataKey::PayoutConfig(username.clone());
+        if targets.is_empty() {
+            env.storage().persistent().remove(&key);
+            emit_payout_config_set(&env, &username, &targets);
+            return Ok(());
+        }
+
+        if targets.len() > MAX_PAYOUT_TARGETS {
+            return Err(Error::InvalidPayoutConfig);
+        }
+        let mut total: u32 = 0;
+        for (_, bps) in targets.iter() {
+            if bps == 0 || bps > BPS_BASE {
+                return Err(Error::InvalidPercentage);
+            }
+            total = total.saturating_add(bps);
+        }
+        if total != BPS_BASE {
+            return Err(Error::InvalidPayoutConfig);
+        }
+
+        env.storage().persistent().set(&key, &targets);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_payout_config_set(&env, &username, &targets);
+        Ok(())
+    }
+
+    /// Sets the share of the pool, in BPS, paid to whoever triggers
+    /// `distribute` for this user. Capped at `MAX_KEEPER_BOUNTY`; pass 0 to
+    /// disable the bounty.
+    pub fn set_keeper_bounty(
+        env: Env,
+        caller: Address,
+        username: String,
+        bps: u32,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        if bps > MAX_KEEPER_BOUNTY {
+            return Err(Error::InvalidPercentage);
+        }
+
+        let key = DataKey::KeeperBounty(username.clone());
+        env.storage().persistent().set(&key, &bps);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_keeper_bounty_set(&env, &username, bps);
+        Ok(())
+    }
+
+    /// Withdraws the keeper rewards earned by `keeper` in `asset`. `to`
+    /// defaults to the keeper. Returns the amount transferred.
+    pub fn claim_keeper_rewards(
+        env: Env,
+        keeper: Address,
+        asset: Address,
+        to: Option<Address>,
+    ) -> Result<i128, Error> {
+        keeper.require_auth();
+
+        let rewards_key = DataKey::KeeperRewards(keeper.clone(), asset.clone());
+        let rewards: i128 = env
+            .storage()
+            .persistent()
+            .get(&rewards_key)
+            .unwrap_or(0);
+
+        if rewards == 0 {
+            return Err(Error::NothingToDistribute);
+        }
+
+        let recipient = to.unwrap_or(keeper.clone());
+
+        token::Client::new(&env, &asset).transfer(
+            &env.current_contract_address(),
+            &recipient,
+            &rewards,
+        );
+
+        storage_add(&env, &DataKey::PaidTo(recipient.clone(), asset.clone()), rewards);
+        env.storage().persistent().set(&rewards_key, &0i128);
+
+        emit_keeper_claimed(&env, &keeper, &recipient, &asset, rewards);
+        Ok(rewards)
+    }
+
+    /// Opens a quadratic funding round. The caller funds `matching_pot` up
+    /// front; donations to `eligible` usernames in `asset` between
+    /// `start_ledger` and `end_ledger` (inclusive) count towards the match,
+    /// once the username's owner has joined the round (see `join_round`).
+    pub fn create_round(
+        env: Env,
+        caller: Address,
+        asset: Address,
+        matching_pot: i128,
+        start_ledger: u32,
+        end_ledger: u32,
+        eligible: Vec<String>,
+    ) -> Result<u32, Error> {
+        caller.require_auth();
+
+        if matching_pot <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if start_ledger >= end_ledger || end_ledger < env.ledger().sequence() {
+            return Err(Error::InvalidRound);
+        }
+        if eligible.is_empty() || eligible.len() > MAX_ROUND_RECIPIENTS {
+            return Err(Error::InvalidRound);
+        }
+
+        for i in 0..eligible.len() {
+            let username = eligible.get(i).unwrap();
+            if !env
+                .storage()
+                .persistent()
+                .has(&DataKey::Owner(username.clone()))
+            {
+                return Err(Error::RecipientNotRegistered);
+            }
+            if eligible.first_index_of(&username) != Some(i) {
+                return Err(Error::InvalidRound);
+            }
+        }
+
+        token::Client::new(&env, &asset).transfer(
+            &caller,
+            env.current_contract_address(),
+            &matching_pot,
+        );
+
+        let round_id: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::RoundCount)
+            .unwrap_or(0);
+        env.storage()
+            .persistent()
+            .set(&DataKey::RoundCount, &(round_id + 1));
+        env.storage()
+            .persistent()
+            .extend_ttl(&DataKey::RoundCount, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        let round = Round {
+            creator: caller.clone(),
+            asset: asset.clone(),
+            matching_pot,
+            start_ledger,
+            end_ledger,
+            eligible: eligible.clone(),
+            finalized: false,
+        };
+        Self::save_round(&env, round_id, &round);
+
+        emit_round_created(&env, round_id, &caller, &asset, matching_pot);
+        Ok(round_id)
+    }
+
+    /// Opts a username into a round it is eligible in, so donations to it
+    /// count towards the match from now until the round ends. Only the
+    /// owner can call this, so a round creator can't fill the user's
+    /// `MAX_USER_ROUNDS` slots. Joining twice is a no-op.
+    pub fn join_round(
+        env: Env,
+        caller: Address,
+        username: String,
+        round_id: u32,
+    ) -> Result<(), Error> {
+        caller.require_auth();
+        Self::assert_owner(&env, &caller, &username)?;
+
+        let round: Round = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Round(round_id))
+            .ok_or(Error::RoundNotFound)?;
+        if env.ledger().sequence() > round.end_ledger {
+            return Err(Error::InvalidRound);
+        }
+        if !round.eligible.contains(&username) {
+            return Err(Error::NotEligible);
+        }
+
+        let mut rounds = Self::open_rounds(&env, &username);
+        if rounds.contains(round_id) {
+            return Ok(());
+        }
+        if rounds.len() >= MAX_USER_ROUNDS {
+            return Err(Error::TooManyRounds);
+        }
+        rounds.push_back(round_id);
+        let key = DataKey::UserRounds(username.clone());
+        env.storage().persistent().set(&key, &rounds);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        emit_round_joined(&env, &username, round_id);
+        Ok(())
+    }
+
+    /// Permissionless once the round has ended. Splits the matching pot in
+    /// proportion to each username's quadratic match,
+    /// `(sum of sqrt(contribution))^2 - sum of contributions`, and credits
+    /// the allocations to the recipients' pools so they cascade like any
+    /// other donation. Rounding dust, or the whole pot if nothing was
+    /// matched, is returned to the round creator. Returns the total matched.
+    pub fn finalize_round(env: Env, round_id: u32) -> Result<i128, Error> {
+        let mut round: Round = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Round(round_id))
+            .ok_or(Error::RoundNotFound)?;
+
+        if round.finalized {
+            return Err(Error::RoundAlreadyFinalized);
+        }
+        if env.ledger().sequence() <= round.end_ledger {
+            return Err(Error::RoundNotEnded);
+        }
+
+        let zero = I256::from_i128(&env, 0);
+        let mut matches: Vec<I256> = Vec::new(&env);
+        let mut total_match = zero.clone();
+
+        for username in round.eligible.iter() {
+            let sqrt_sum: i128 = env
+                .storage()
+                .persistent()
+                .get(&DataKey::RoundSqrtSum(round_id, username.clone()))
+                .unwrap_or(0);
+            let total: i128 = env
+                .storage()
+                .persistent()
+                .get(&DataKey::RoundTotal(round_id, username))
+                .unwrap_or(0);
+
+            let sqrt_sum = I256::from_i128(&env, sqrt_sum);
+            let mut qf = sqrt_sum.mul(&sqrt_sum).sub(&I256::from_i128(&env, total));
+            if qf < zero {
+                qf = zero.clone();
+            }
+            total_match = total_match.add(&qf);
+            matches.push_back(qf);
+        }
+
+        let mut matched: i128 = 0;
+        if total_match > zero {
+            let pot = I256::from_i128(&env, round.matching_pot);
+            for i in 0..round.eligible.len() {
+                let username = round.eligible.get(i).unwrap();
+                let allocation = pot
+                    .mul(&matches.get(i).unwrap())
+                    .div(&total_match)
+                    .to_i128()
+                    .unwrap();
+                if allocation == 0 {
+                    continue;
+                }
+                matched += allocation;
+
+                Self::credit_pool(&env, &username, &round.asset, allocation);
+                storage_add(
+                    &env,
+                    &DataKey::TotalReceived(username.clone(), round.asset.clone()),
+                    allocation,
+                );
+
+                emit_round_matched(&env, &username, round_id, &round.asset, allocation);
+            }
+        }
+
+        let returned = round.matching_pot - matched;
+        if returned > 0 {
+            token::Client::new(&env, &round.asset).transfer(
+                &env.current_contract_address(),
+                &round.creator,
+                &returned,
+            );
+        }
+
+        for username in round.eligible.iter() {
+            let key = DataKey::UserRounds(username);
+            let rounds: Vec<u32> = env
+                .storage()
+                .persistent()
+                .get(&key)
+                .unwrap_or(Vec::new(&env));
+            let mut remaining: Vec<u32> = Vec::new(&env);
+            for id in rounds.iter() {
+                if id != round_id {
+                    remaining.push_back(id);
+                }
+            }
+            env.storage().persistent().set(&key, &remaining);
+        }
+
+        round.finalized = true;
+        Self::save_round(&env, round_id, &round);
+
+        emit_round_finalized(&env, round_id, matched, returned);
+        Ok(matched)
+    }
+
+    pub fn get_pool(env: Env, username: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Pool(username, asset))
+            .unwrap_or(0)
+    }
+
+    /// Up to `limit` of the assets the user has ever received, in order of
+    /// first receipt, starting at the `start`th.
+    pub fn get_assets(env: Env, username: String, start: u32, limit: u32) -> Vec<Address> {
+        let count = Self::get_asset_count(env.clone(), username.clone());
+
+        let mut assets: Vec<Address> = Vec::new(&env);
+        let to = start.saturating_add(limit).min(count);
+        for index in start..to {
+            let asset: Option<Address> = env
+                .storage()
+                .persistent()
+                .get(&DataKey::Asset(username.clone(), index));
+            if let Some(asset) = asset {
+                assets.push_back(asset);
+            }
+        }
+        assets
+    }
+
+    /// Number of distinct assets the user has ever received.
+    pub fn get_asset_count(env: Env, username: String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::AssetCount(username))
+            .unwrap_or(0)
+    }
+
+    pub fn get_rules(env: Env, username: String) -> Map<String, u32> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Rules(username))
+            .unwrap_or(Map::new(&env))
+    }
+
+    pub fn get_owner(env: Env, username: String) -> Option<Address> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Owner(username))
+    }
+
+    pub fn get_total_received(env: Env, username: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::TotalReceived(username, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_total_received_from_others(
+        env: Env,
+        username: String,
+        asset: Address,
+    ) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::TotalReceivedFromOthers(username, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_total_forwarded(env: Env, username: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::TotalForwarded(username, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_min_distribution(env: Env, username: String, asset: Address) -> i128 {
+        Self::min_distribution(&env, &username, &asset)
+    }
+
+    pub fn get_auto_distribute(env: Env, username: String) -> bool {
+        env.storage()
+            .persistent()
+            .get(&DataKey::AutoDistribute(username))
+            .unwrap_or(false)
+    }
+
+    pub fn get_operator(env: Env, username: String, operator: Address) -> Option<OperatorGrant> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Operator(username, operator))
+    }
+
+    /// Addresses holding an operator grant, including expired ones.
+    pub fn get_operators(env: Env, username: String) -> Vec<Address> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Operators(username))
+            .unwrap_or(Vec::new(&env))
+    }
+
+    pub fn get_vesting_config(env: Env, username: String) -> Option<VestingConfig> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::VestingConfig(username))
+    }
+
+    pub fn get_vesting(env: Env, schedule_id: u32) -> Option<VestingSchedule> {
+        env.storage().persistent().get(&DataKey::Vesting(schedule_id))
+    }
+
+    /// Amount of a schedule that has vested and can be withdrawn now.
+    pub fn get_withdrawable(env: Env, schedule_id: u32) -> i128 {
+        match Self::get_vesting(env.clone(), schedule_id) {
+            Some(schedule) => Self::vested(&env, &schedule) - schedule.withdrawn,
+            None => 0,
+        }
+    }
+
+    /// Ids of the schedules started for a beneficiary, oldest first,
+    /// looking at `limit` of them from the `start`th on. Fully withdrawn
+    /// schedules are skipped, so a page can hold fewer than `limit`.
+    pub fn get_beneficiary_vesting(
+        env: Env,
+        beneficiary: Address,
+        start: u32,
+        limit: u32,
+    ) -> Vec<u32> {
+        let count = Self::get_beneficiary_vesting_count(env.clone(), beneficiary.clone());
+        Self::vesting_page(&env, count, start, limit, |index| {
+            DataKey::BeneficiaryVesting(beneficiary.clone(), index)
+        })
+    }
+
+    /// Number of schedules ever started for a beneficiary.
+    pub fn get_beneficiary_vesting_count(env: Env, beneficiary: Address) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::BeneficiaryVestingCount(beneficiary))
+            .unwrap_or(0)
+    }
+
+    /// Ids of the user's schedules, oldest first, looking at `limit` of
+    /// them from the `start`th on. Fully withdrawn schedules are skipped.
+    pub fn get_user_vesting(env: Env, username: String, start: u32, limit: u32) -> Vec<u32> {
+        let count = Self::get_user_vesting_count(env.clone(), username.clone());
+        Self::vesting_page(&env, count, start, limit, |index| {
+            DataKey::UserVesting(username.clone(), index)
+        })
+    }
+
+    /// Number of schedules the user's claims ever started.
+    pub fn get_user_vesting_count(env: Env, username: String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::UserVestingCount(username))
+            .unwrap_or(0)
+    }
+
+    pub fn get_preferred_asset(env: Env, username: String) -> Option<Address> {
+        env.storage().persistent().get(&DataKey::PreferredAsset(username))
+    }
+
+    pub fn get_router(env: Env, username: String) -> Option<Address> {
+        env.storage().persistent().get(&DataKey::Router(username))
+    }
+
+    pub fn get_push_payout(env: Env, username: String) -> bool {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PushPayout(username))
+            .unwrap_or(false)
+    }
+
+    /// Payout addresses and their BPS (empty = claims pay the owner).
+    pub fn get_payout_config(env: Env, username: String) -> Map<Address, u32> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PayoutConfig(username))
+            .unwrap_or(Map::new(&env))
+    }
+
+    pub fn get_keeper_bounty(env: Env, username: String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::KeeperBounty(username))
+            .unwrap_or(0)
+    }
+
+    pub fn get_keeper_rewards(env: Env, keeper: Address, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::KeeperRewards(keeper, asset))
+            .unwrap_or(0)
+    }
+
+    /// Carried-over shares `upstream` owes `downstream`: shares that were
+    /// below the distribution threshold and will be forwarded once the
+    /// amount due crosses it.
+    pub fn get_owed(env: Env, upstream: String, downstream: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Owed(EdgeKey {
+                upstream,
+                downstream,
+                asset,
+            }))
+            .unwrap_or(0)
+    }
+
+    /// Hop-limited part of a pool, keyed by how many hops the funds may
+    /// still be forwarded. The rest of the pool cascades without limit.
+    pub fn get_pool_depths(env: Env, username: String, asset: Address) -> Map<u32, i128> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::DepthPool(username, asset))
+            .unwrap_or(Map::new(&env))
+    }
+
+    /// Hop-limited part of the shares carried over for a dependent, keyed by
+    /// the hops left once they reach the dependent.
+    pub fn get_owed_depths(
+        env: Env,
+        upstream: String,
+        downstream: String,
+        asset: Address,
+    ) -> Map<u32, i128> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::OwedDepth(EdgeKey {
+                upstream,
+                downstream,
+                asset,
+            }))
+            .unwrap_or(Map::new(&env))
+    }
+
+    /// How much `username` regifted to `target_username` from their
+    /// unclaimed balance.
+    pub fn get_regifted(
+        env: Env,
+        username: String,
+        target_username: String,
+        asset: Address,
+    ) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Regifted(EdgeKey {
+                upstream: username,
+                downstream: target_username,
+                asset,
+            }))
+            .unwrap_or(0)
+    }
+
+    /// Total `username` regifted to other usernames.
+    pub fn get_total_regifted(env: Env, username: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::TotalRegifted(username, asset))
+            .unwrap_or(0)
+    }
+
+    /// A spender's allowance, or None if there is none or it was set
+    /// before the latest ownership transfer.
+    pub fn get_allowance(
+        env: Env,
+        username: String,
+        spender: Address,
+        asset: Address,
+    ) -> Option<Allowance> {
+        let epoch = Self::owner_epoch(&env, &username);
+        env.storage()
+            .persistent()
+            .get(&DataKey::Allowance(AllowanceKey {
+                username,
+                spender,
+                asset,
+            }))
+            .filter(|allowance: &Allowance| allowance.epoch == epoch)
+    }
+
+    /// What a spender can still withdraw in the current period.
+    pub fn get_remaining_allowance(
+        env: Env,
+        username: String,
+        spender: Address,
+        asset: Address,
+    ) -> i128 {
+        match Self::get_allowance(env.clone(), username, spender, asset) {
+            Some(mut allowance) => {
+                Self::roll_allowance(&env, &mut allowance);
+                allowance.amount - allowance.spent
+            }
+            None => 0,
+        }
+    }
+
+    pub fn get_payroll(env: Env, payroll_id: u32) -> Option<PayrollEntry> {
+        env.storage().persistent().get(&DataKey::Payroll(payroll_id))
+    }
+
+    /// Ids of the user's payroll entries, in order of creation.
+    pub fn get_user_payroll(env: Env, username: String) -> Vec<u32> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::UserPayroll(username))
+            .unwrap_or(Vec::new(&env))
+    }
+
+    pub fn get_expiry_policy(env: Env, username: String) -> Option<ExpiryPolicy> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::ExpiryPolicy(username))
+    }
+
+    /// Ledger of the user's latest activity: registration, any call the
+    /// owner signed, or a claim, push payout, payroll payment or allowance
+    /// withdrawal.
+    pub fn get_last_claim(env: Env, username: String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::LastClaim(username))
+            .unwrap_or(0)
+    }
+
+    pub fn get_unclaimed(env: Env, username: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Unclaimed(username, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_donor_to_user(
+        env: Env,
+        donor: Address,
+        username: String,
+        asset: Address,
+    ) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::DonorToUser(DonorKey {
+                donor,
+                username,
+                asset,
+            }))
+            .unwrap_or(0)
+    }
+
+    pub fn get_donor_total(env: Env, donor: Address, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::DonorTotal(donor, asset))
+            .unwrap_or(0)
+    }
+
+    /// Self-declared attribution: donations made through `donor_override`
+    /// naming this address without its signature.
+    pub fn get_unverified_donor_to_user(
+        env: Env,
+        donor: Address,
+        username: String,
+        asset: Address,
+    ) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::UnverifiedDonorToUser(DonorKey {
+                donor,
+                username,
+                asset,
+            }))
+            .unwrap_or(0)
+    }
+
+    pub fn get_unverified_donor_total(env: Env, donor: Address, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::UnverifiedDonorTotal(donor, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_grand_total(env: Env, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::GrandTotal(asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_paid_to(env: Env, address: Address, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PaidTo(address, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_refund_window(env: Env, username: String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::RefundWindow(username))
+            .unwrap_or(0)
+    }
+
+    pub fn get_refundable_donation(
+        env: Env,
+        donor: Address,
+        username: String,
+        asset: Address,
+    ) -> Option<RefundableDonation> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::LastDonation(DonorKey {
+                donor,
+                username,
+                asset,
+            }))
+    }
+
+    /// Total held in escrow for a username that is not registered yet.
+    pub fn get_escrow(env: Env, username: String, asset: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::EscrowTotal(username, asset))
+            .unwrap_or(0)
+    }
+
+    pub fn get_escrow_deposit(
+        env: Env,
+        donor: Address,
+        username: String,
+        asset: Address,
+    ) -> Option<EscrowDeposit> {
+        if env
+            .storage()
+            .persistent()
+            .has(&DataKey::Owner(username.clone()))
+        {
+            return None;
+        }
+        env.storage()
+            .persistent()
+            .get(&DataKey::Escrow(DonorKey {
+                donor,
+                username,
+                asset,
+            }))
+    }
+
+    /// Number of pools waiting in the distribution queue. Entries whose pool
+    /// was distributed directly in the meantime are included until popped.
+    pub fn get_queue_length(env: Env) -> u32 {
+        let head: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::QueueHead)
+            .unwrap_or(0);
+        let tail: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::QueueTail)
+            .unwrap_or(0);
+        tail - head
+    }
+
+    /// Up to `limit` queue entries, starting `start` entries from the front.
+    pub fn get_queue(env: Env, start: u32, limit: u32) -> Vec<QueueEntry> {
+        let head: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::QueueHead)
+            .unwrap_or(0);
+        let tail: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::QueueTail)
+            .unwrap_or(0);
+
+        let mut entries: Vec<QueueEntry> = Vec::new(&env);
+        let from = head.saturating_add(start);
+        let to = from.saturating_add(limit).min(tail);
+        for index in from..to {
+            let entry: Option<QueueEntry> =
+                env.storage().persistent().get(&DataKey::QueueItem(index));
+            if let Some(entry) = entry {
+                entries.push_back(entry);
+            }
+        }
+        entries
+    }
+
+    pub fn get_round(env: Env, round_id: u32) -> Option<Round> {
+        env.storage().persistent().get(&DataKey::Round(round_id))
+    }
+
+    pub fn get_round_count(env: Env) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::RoundCount)
+            .unwrap_or(0)
+    }
+
+    pub fn get_round_contribution(
+        env: Env,
+        round_id: u32,
+        username: String,
+        donor: Address,
+    ) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::RoundContribution(RoundDonorKey {
+                round_id,
+                username,
+                donor,
+            }))
+            .unwrap_or(0)
+    }
+
+    pub fn get_round_total(env: Env, round_id: u32, username: String) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::RoundTotal(round_id, username))
+            .unwrap_or(0)
+    }
+
+    /// Splits `amount` of the user's pool (the whole pool if `None`)
+    /// according to their rules.
+    fn distribute_internal(
+        env: &Env,
+        username: &String,
+        asset: &Address,
+        amount: Option<i128>,
+        min_distribution: i128,
+        keeper: Option<&Address>,
+    ) -> Result<DistributionReport, Error> {
+        let report = Self::plan_distribution(
+            env,
+            username,
+            asset,
+            amount,
+            min_distribution,
+            keeper.is_some(),
+        )?;
+
+        if let Some(keeper) = keeper {
+            if report.keeper_reward > 0 {
+                storage_add(
+                    env,
+                    &DataKey::KeeperRewards(keeper.clone(), asset.clone()),
+                    report.keeper_reward,
+                );
+            }
+        }
+
+        // Hop-limited funds are drawn first; each forwarded share carries
+        // its part of them one hop further down.
+        let depth_key = DataKey::DepthPool(username.clone(), asset.clone());
+        let (drawn, rest) = Self::draw_depths(
+            env,
+            &Self::get_pool_depths(env.clone(), username.clone(), asset.clone()),
+            report.amount,
+        );
+        Self::save_depths(env, &depth_key, &rest);
+        let base = report.amount - drawn.get(0).unwrap_or(0);
+
+        for item in report.shares.iter() {
+            let edge = EdgeKey {
+                upstream: username.clone(),
+                downstream: item.recipient.clone(),
+                asset: asset.clone(),
+            };
+            let owed_key = DataKey::Owed(edge.clone());
+            let depths = Self::tag_share(env, &drawn, base, item.share);
+
+            if item.skipped {
+                storage_add(env, &owed_key, item.share);
+                Self::add_depths(env, &DataKey::OwedDepth(edge), &depths);
+                Self::track_owed_asset(env, username, asset);
+                continue;
+            }
+
+            let mut depths = depths;
+            if item.carried_over > 0 {
+                env.storage().persistent().remove(&owed_key);
+                let owed_depths = Self::take_owed_depths(env, username, &item.recipient, asset);
+                for (hops, value) in owed_depths.iter() {
+                    depths.set(hops, depths.get(hops).unwrap_or(0) + value);
+                }
+            }
+
+            Self::forward(
                 env,
-                &DataKey::Unclaimed(username.clone(), asset.clone()),
-                owner_share,
+                username,
+                &item.recipient,
+                asset,
+                item.share + item.carried_over,
+                &depths,
             );
         }
 
-        env.storage().persistent().set(&pool_key, &0i128);
+        if report.owner_share > 0 {
+            // Funds that must vest are only released through `claim`.
+            let pushed = if Self::get_push_payout(env.clone(), username.clone())
+                && Self::get_vesting_config(env.clone(), username.clone()).is_none()
+            {
+                Self::push_payout(env, username, asset, report.owner_share)
+            } else {
+                0
+            };
+            if report.owner_share > pushed {
+                storage_add(
+                    env,
+                    &DataKey::Unclaimed(username.clone(), asset.clone()),
+                    report.owner_share - pushed,
+                );
+            }
+        }
+
+        let remaining = report.pool - report.amount;
+        env.storage().persistent().set(
+            &DataKey::Pool(username.clone(), asset.clone()),
+            &remaining,
+        );
+        if remaining > 0 {
+            Self::enqueue(env, username, asset);
+        }
+        Self::bump_pool_epoch(env, username, asset);
+
+        emit_distributed(
+            env,
+            username,
+            asset,
+            report.amount,
+            report.min_distribution,
+            keeper,
+            report.keeper_reward,
+        );
+        Ok(report)
+    }
+
+    /// Works out how a distribution would split the pool without touching
+    /// state. Shared by `distribute_internal` and `quote_distribution` so the
+    /// two can never disagree.
+    fn plan_distribution(
+        env: &Env,
+        username: &String,
+        asset: &Address,
+        amount: Option<i128>,
+        min_distribution: i128,
+        with_keeper: bool,
+    ) -> Result<DistributionReport, Error> {
+        if !env
+            .storage()
+            .persistent()
+            .has(&DataKey::Owner(username.clone()))
+        {
+            return Err(Error::UserNotFound);
+        }
+
+        let rules: Map<String, u32> = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Rules(username.clone()))
+            .ok_or(Error::RulesNotSet)?;
+
+        // A caller can lower the owner's threshold but never raise it, so a
+        // permissionless distribution can't hold back every share.
+        let min_distribution = min_distribution.min(Self::min_distribution(env, username, asset));
+
+        let pool: i128 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Pool(username.clone(), asset.clone()))
+            .unwrap_or(0);
+
+        if pool == 0 {
+            return Err(Error::NothingToDistribute);
+        }
+
+        let amount = amount.unwrap_or(pool);
+        if amount <= 0 || amount > pool {
+            return Err(Error::InvalidAmount);
+        }
+
+        // The keeper bounty comes off the top; the rules split what is left.
+        let mut keeper_reward: i128 = 0;
+        if with_keeper {
+            let bounty: u32 = env
+                .storage()
+                .persistent()
+                .get(&DataKey::KeeperBounty(username.clone()))
+                .unwrap_or(0);
+            keeper_reward = amount * bounty as i128 / (BPS_BASE as i128);
+        }
+        let splittable = amount - keeper_reward;
+
+        // Funds whose hop limit has run out stay with the owner; only the
+        // rest is split by the rules.
+        let (drawn, _) = Self::draw_depths(
+            env,
+            &Self::get_pool_depths(env.clone(), username.clone(), asset.clone()),
+            amount,
+        );
+        let hop_limited = drawn.get(0).unwrap_or(0).min(splittable);
+        let forwardable = splittable - hop_limited;
+
+        // Shares below the threshold are not forfeited: they accrue on the
+        // edge and go out with a later distribution once the total due is
+        // large enough.
+        let mut shares: Vec<RecipientShare> = Vec::new(env);
+        let mut total_shared: i128 = 0;
+        let mut total_forwarded: i128 = 0;
+        let keys = rules.keys();
+
+        for i in 0..keys.len() {
+            let recipient = keys.get(i).unwrap();
+            let pct = rules.get(recipient.clone()).unwrap() as i128;
+            let share = forwardable * pct / (BPS_BASE as i128);
+            total_shared += share;
+
+            let carried_over: i128 = env
+                .storage()
+                .persistent()
+                .get(&DataKey::Owed(EdgeKey {
+                    upstream: username.clone(),
+                    downstream: recipient.clone(),
+                    asset: asset.clone(),
+                }))
+                .unwrap_or(0);
+            let skipped = share + carried_over < min_distribution;
+            if !skipped {
+                total_forwarded += share + carried_over;
+            }
+
+            shares.push_back(RecipientShare {
+                recipient,
+                share,
+                carried_over,
+                skipped,
+            });
+        }
+
+        Ok(DistributionReport {
+            asset: asset.clone(),
+            pool,
+            amount,
+            min_distribution,
+            keeper_reward,
+            shares,
+            owner_share: splittable - total_shared,
+            total_forwarded,
+            hop_limited,
+        })
+    }
+
+    /// Moves `amount` from `upstream`'s distribution into `downstream`'s
+    /// pool and books it in the cascade counters. `depths` is the
+    /// hop-limited part of `amount`, already moved down one hop.
+    fn forward(
+        env: &Env,
+        upstream: &String,
+        downstream: &String,
+        asset: &Address,
+        amount: i128,
+        depths: &Map<u32, i128>,
+    ) {
+        Self::credit_pool(env, downstream, asset, amount);
+        Self::add_depths(
+            env,
+            &DataKey::DepthPool(downstream.clone(), asset.clone()),
+            depths,
+        );
+        storage_add(
+            env,
+            &DataKey::TotalReceived(downstream.clone(), asset.clone()),
+            amount,
+        );
+        storage_add(
+            env,
+            &DataKey::TotalReceivedFromOthers(downstream.clone(), asset.clone()),
+            amount,
+        );
+        storage_add(
+            env,
+            &DataKey::TotalForwarded(upstream.clone(), asset.clone()),
+            amount,
+        );
+        emit_forwarded(env, upstream, downstream, asset, amount);
+    }
+
+    fn min_distribution(env: &Env, username: &String, asset: &Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::MinDistribution(username.clone(), asset.clone()))
+            .unwrap_or(0)
+    }
+
+    /// `fallback` receives the balance when there is neither a `to` nor a
+    /// payout config.
+    fn do_claim(
+        env: &Env,
+        fallback: &Address,
+        username: &String,
+        asset: &Address,
+        to: Option<Address>,
+    ) -> Result<i128, Error> {
+        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
+        let unclaimed: i128 = env
+            .storage()
+            .persistent()
+            .get(&unclaimed_key)
+            .unwrap_or(0);
+
+        if unclaimed == 0 {
+            return Err(Error::NothingToDistribute);
+        }
+
+        let payees = match to {
+            Some(to) => Map::from_array(env, [(to, unclaimed)]),
+            None => Self::split_payout(env, username, fallback, unclaimed),
+        };
+
+        env.storage().persistent().set(&unclaimed_key, &0i128);
+
+        Self::pay_out(env, username, asset, &payees);
+        Ok(unclaimed)
+    }
+
+    /// Transfers each payee their amount of `asset` and books it in `PaidTo`,
+    /// or starts a vesting schedule for each if the user has vesting on.
+    fn pay_out(env: &Env, username: &String, asset: &Address, payees: &Map<Address, i128>) {
+        Self::touch_last_claim(env, username);
+        let vesting: Option<VestingConfig> = env
+            .storage()
+            .persistent()
+            .get(&DataKey::VestingConfig(username.clone()));
+        let token = token::Client::new(env, asset);
+        for (recipient, amount) in payees.iter() {
+            if amount == 0 {
+                continue;
+            }
+            if let Some(vesting) = &vesting {
+                Self::start_vesting(env, username, asset, &recipient, amount, vesting);
+                continue;
+            }
+            token.transfer(&env.current_contract_address(), &recipient, &amount);
+            storage_add(env, &DataKey::PaidTo(recipient.clone(), asset.clone()), amount);
+            emit_claimed(env, username, &recipient, asset, amount);
+        }
+    }
+
+    fn start_vesting(
+        env: &Env,
+        username: &String,
+        asset: &Address,
+        beneficiary: &Address,
+        amount: i128,
+        vesting: &VestingConfig,
+    ) {
+        let schedule_id: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::VestingCount)
+            .unwrap_or(0);
+        env.storage()
+            .persistent()
+            .set(&DataKey::VestingCount, &(schedule_id + 1));
+        env.storage()
+            .persistent()
+            .extend_ttl(&DataKey::VestingCount, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        let schedule = VestingSchedule {
+            username: username.clone(),
+            asset: asset.clone(),
+            beneficiary: beneficiary.clone(),
+            total: amount,
+            withdrawn: 0,
+            start_ledger: env.ledger().sequence(),
+            cliff: vesting.cliff,
+            duration: vesting.duration,
+        };
+        Self::save_vesting(env, schedule_id, &schedule);
+
+        // One entry per schedule, so naming an address as a payee can't
+        // grow a single entry until claims to it fail.
+        let count_key = DataKey::BeneficiaryVestingCount(beneficiary.clone());
+        let index = Self::get_beneficiary_vesting_count(env.clone(), beneficiary.clone());
+        let item_key = DataKey::BeneficiaryVesting(beneficiary.clone(), index);
+        Self::index_vesting(env, &count_key, &item_key, index, schedule_id);
+
+        let count_key = DataKey::UserVestingCount(username.clone());
+        let index = Self::get_user_vesting_count(env.clone(), username.clone());
+        let item_key = DataKey::UserVesting(username.clone(), index);
+        Self::index_vesting(env, &count_key, &item_key, index, schedule_id);
+
+        emit_vesting_created(env, username, schedule_id, beneficiary, asset, amount);
+    }
+
+    /// Stores a schedule, or deletes it once everything in it has been
+    /// withdrawn. Its index entries stay behind and are skipped by the
+    /// getters.
+    fn save_vesting(env: &Env, schedule_id: u32, schedule: &VestingSchedule) {
+        let key = DataKey::Vesting(schedule_id);
+        if schedule.withdrawn < schedule.total {
+            env.storage().persistent().set(&key, schedule);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+        } else {
+            env.storage().persistent().remove(&key);
+        }
+    }
+
+    /// Appends `schedule_id` at `index` of a vesting index and bumps its
+    /// count.
+    fn index_vesting(
+        env: &Env,
+        count_key: &DataKey,
+        item_key: &DataKey,
+        index: u32,
+        schedule_id: u32,
+    ) {
+        env.storage().persistent().set(item_key, &schedule_id);
+        env.storage()
+            .persistent()
+            .extend_ttl(item_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+        env.storage().persistent().set(count_key, &(index + 1));
+        env.storage()
+            .persistent()
+            .extend_ttl(count_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    /// Ids stored at `start..start + limit` of a vesting index whose
+    /// schedules still exist.
+    fn vesting_page(
+        env: &Env,
+        count: u32,
+        start: u32,
+        limit: u32,
+        item_key: impl Fn(u32) -> DataKey,
+    ) -> Vec<u32> {
+        let mut ids: Vec<u32> = Vec::new(env);
+        let to = start.saturating_add(limit).min(count);
+        for index in start..to {
+            let id: Option<u32> = env.storage().persistent().get(&item_key(index));
+            if let Some(id) = id {
+                if env.storage().persistent().has(&DataKey::Vesting(id)) {
+                    ids.push_back(id);
+                }
+            }
+        }
+        ids
+    }
+
+    /// Amount of a schedule vested by the current ledger.
+    fn vested(env: &Env, schedule: &VestingSchedule) -> i128 {
+        let elapsed = env.ledger().sequence().saturating_sub(schedule.start_ledger);
+        if elapsed < schedule.cliff {
+            return 0;
+        }
+        if elapsed >= schedule.duration {
+            return schedule.total;
+        }
+        schedule.total * elapsed as i128 / schedule.duration as i128
+    }
+
+    /// Converts up to `amount` of the contract's `asset_in` into
+    /// `asset_out` through `router`. Both sides are measured from the
+    /// contract's own balances, not taken from the router's word, and the
+    /// output must reach `min_out`. The router's approval is reset
+    /// afterwards. Returns the input spent and the output received.
+    fn swap(
+        env: &Env,
+        router: &Address,
+        asset_in: &Address,
+        asset_out: &Address,
+        amount: i128,
+        min_out: i128,
+    ) -> Result<(i128, i128), Error> {
+        let this = env.current_contract_address();
+        let token_in = token::Client::new(env, asset_in);
+        let token_out = token::Client::new(env, asset_out);
+        let before_in = token_in.balance(&this);
+        let before_out = token_out.balance(&this);
+
+        token_in.approve(&this, router, &amount, &env.ledger().sequence());
+        SwapRouterClient::new(env, router).swap(&this, asset_in, asset_out, &amount, &min_out);
+        token_in.approve(&this, router, &0, &env.ledger().sequence());
+
+        let spent = before_in - token_in.balance(&this);
+        let out = token_out.balance(&this) - before_out;
+        if out < min_out || out <= 0 {
+            return Err(Error::SlippageExceeded);
+        }
+        Ok((spent, out))
+    }
+
+    /// Sends `amount` to the user's payout addresses (or the owner) without
+    /// failing the caller: a transfer that is rejected is skipped and
+    /// reported. Returns the amount actually sent.
+    fn push_payout(env: &Env, username: &String, asset: &Address, amount: i128) -> i128 {
+        let owner: Address = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Owner(username.clone()))
+            .unwrap();
+        let token = token::Client::new(env, asset);
+
+        let mut pushed: i128 = 0;
+        for (recipient, share) in Self::split_payout(env, username, &owner, amount).iter() {
+            if share == 0 {
+                continue;
+            }
+            let sent = token.try_transfer(&env.current_contract_address(), &recipient, &share);
+            if !matches!(sent, Ok(Ok(()))) {
+                emit_push_failed(env, username, &recipient, asset, share);
+                continue;
+            }
+            storage_add(env, &DataKey::PaidTo(recipient.clone(), asset.clone()), share);
+            emit_claimed(env, username, &recipient, asset, share);
+            pushed += share;
+        }
+        if pushed > 0 {
+            Self::touch_last_claim(env, username);
+        }
+        pushed
+    }
+
+    /// Splits `amount` between the user's payout addresses, or gives it all
+    /// to `fallback` if none are configured. Rounding dust goes to the payout
+    /// address that sorts first in the map, whatever order it was set in.
+    fn split_payout(
+        env: &Env,
+        username: &String,
+        fallback: &Address,
+        amount: i128,
+    ) -> Map<Address, i128> {
+        let targets = Self::get_payout_config(env.clone(), username.clone());
+        if targets.is_empty() {
+            return Map::from_array(env, [(fallback.clone(), amount)]);
+        }
+
+        let mut payees: Map<Address, i128> = Map::new(env);
+        let mut paid: i128 = 0;
+        for (address, bps) in targets.iter() {
+            let share = amount * bps as i128 / (BPS_BASE as i128);
+            payees.set(address, share);
+            paid += share;
+        }
+        let first = targets.keys().get(0).unwrap();
+        payees.set(first.clone(), payees.get(first).unwrap() + amount - paid);
+        payees
+    }
+
+    /// Adds `amount` to the user's pool, recording the asset in the user's
+    /// asset index the first time it is received and queueing the pool for
+    /// keepers unless it is already waiting.
+    fn credit_pool(env: &Env, username: &String, asset: &Address, amount: i128) {
+        let pool_key = DataKey::Pool(username.clone(), asset.clone());
+        storage_add(env, &pool_key, amount);
+        if amount > 0 {
+            Self::enqueue(env, username, asset);
+        }
+
+        // One entry per asset, so a user holding many tokens costs no more
+        // to credit than one holding a single token.
+        let seen_key = DataKey::HasAsset(username.clone(), asset.clone());
+        if !env.storage().persistent().has(&seen_key) {
+            env.storage().persistent().set(&seen_key, &true);
+
+            let count_key = DataKey::AssetCount(username.clone());
+            let count: u32 = env.storage().persistent().get(&count_key).unwrap_or(0);
+            let asset_key = DataKey::Asset(username.clone(), count);
+            env.storage().persistent().set(&asset_key, asset);
+            env.storage()
+                .persistent()
+                .extend_ttl(&asset_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+            env.storage().persistent().set(&count_key, &(count + 1));
+            env.storage()
+                .persistent()
+                .extend_ttl(&count_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+        }
+        env.storage()
+            .persistent()
+            .extend_ttl(&seen_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    /// Appends the pool to the distribution queue unless it is already
+    /// waiting in it. `Queued` holds the position of the pool's entry, so an
+    /// entry that was archived doesn't keep the pool out of the queue.
+    fn enqueue(env: &Env, username: &String, asset: &Address) {
+        let queued_key = DataKey::Queued(username.clone(), asset.clone());
+        let queued: Option<u32> = env.storage().persistent().get(&queued_key);
+        if queued.is_some_and(|index| env.storage().persistent().has(&DataKey::QueueItem(index))) {
+            return;
+        }
+
+        let tail: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::QueueTail)
+            .unwrap_or(0);
+        env.storage().persistent().set(&queued_key, &tail);
+        env.storage()
+            .persistent()
+            .extend_ttl(&queued_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        let item_key = DataKey::QueueItem(tail);
+        env.storage().persistent().set(
+            &item_key,
+            &QueueEntry {
+                username: username.clone(),
+                asset: asset.clone(),
+            },
+        );
+        env.storage()
+            .persistent()
+            .extend_ttl(&item_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        env.storage().persistent().set(&DataKey::QueueTail, &(tail + 1));
+        env.storage()
+            .persistent()
+            .extend_ttl(&DataKey::QueueTail, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    /// Converts a donation into the user's preferred asset through their
+    /// router. Donations already in that asset, to users without a
+    /// preference or router, or to unregistered names are left as they are.
+    /// Input the router leaves unspent is returned to `payer`. Returns the
+    /// asset and amount to credit, and how much of the donation was used.
+    fn convert_donation(
+        env: &Env,
+        username: &String,
+        payer: &Address,
+        asset: &Address,
+        amount: i128,
+        min_out: i128,
+    ) -> Result<(Address, i128, i128), Error> {
+        let preferred: Option<Address> = env
+            .storage()
+            .persistent()
+            .get(&DataKey::PreferredAsset(username.clone()));
+        let router: Option<Address> = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Router(username.clone()));
+
+        match (preferred, router) {
+            (Some(preferred), Some(router)) if preferred != *asset => {
+                let (spent, out) = Self::swap(env, &router, asset, &preferred, amount, min_out)?;
+                if spent < amount {
+                    token::Client::new(env, asset).transfer(
+                        &env.current_contract_address(),
+                        payer,
+                        &(amount - spent),
+                    );
+                }
+                Ok((preferred, out, spent))
+            }
+            _ => Ok((asset.clone(), amount, amount)),
+        }
+    }
+
+    /// Books tokens already received from `payer` as a donation, escrowing
+    /// them if the username is not registered yet. `attributed` is a
+    /// self-declared donor other than the payer, if any. Refunds and round
+    /// matching always follow the payer, the only party that signed.
+    /// `source` is the asset and amount the payer sent, before any
+    /// conversion into `asset`.
+    #[allow(clippy::too_many_arguments)]
+    fn credit_donation(
+        env: &Env,
+        username: &String,
+        payer: &Address,
+        attributed: &Option<Address>,
+        source: (&Address, i128),
+        asset: &Address,
+        amount: i128,
+        max_hops: Option<u32>,
+    ) -> Result<(), Error> {
+        if !env
+            .storage()
+            .persistent()
+            .has(&DataKey::Owner(username.clone()))
+        {
+            return Self::escrow_donation(env, username, payer, attributed, asset, amount);
+        }
+
+        Self::credit_pool(env, username, asset, amount);
+        let depths = match max_hops {
+            Some(max_hops) => Map::from_array(env, [(max_hops, amount)]),
+            None => Map::new(env),
+        };
+        Self::add_depths(
+            env,
+            &DataKey::DepthPool(username.clone(), asset.clone()),
+            &depths,
+        );
+        storage_add(
+            env,
+            &DataKey::TotalReceived(username.clone(), asset.clone()),
+            amount,
+        );
+        Self::add_attribution(env, username, payer, attributed, asset, amount);
+        let rounds = Self::record_round_contribution(env, username, payer, asset, amount);
+        Self::record_refundable(
+            env,
+            username,
+            payer,
+            asset,
+            RefundableDonation {
+                amount,
+                ledger: env.ledger().sequence(),
+                epoch: Self::pool_epoch(env, username, asset),
+                rounds,
+                attributed: match attributed {
+                    Some(donor) => Map::from_array(env, [(donor.clone(), amount)]),
+                    None => Map::new(env),
+                },
+                depths,
+            },
+        );
+
+        let donor = attributed.clone().unwrap_or(payer.clone());
+        emit_donated(
+            env,
+            username,
+            &donor,
+            asset,
+            amount,
+            attributed.is_none(),
+            source.0,
+            source.1,
+        );
 
-        emit_distributed(env, username, asset, pool);
+        if env
+            .storage()
+            .persistent()
+            .get(&DataKey::AutoDistribute(username.clone()))
+            .unwrap_or(false)
+        {
+            let min_distribution = Self::min_distribution(env, username, asset);
+            Self::distribute_internal(env, username, asset, None, min_distribution, None)?;
+        }
         Ok(())
     }
 
-    fn do_claim(
+    /// Books a donation (or, with a negative `amount`, its reversal) in the
+    /// donor analytics. Donations the payer made in their own name count as
+    /// verified; self-declared attributions go to the unverified counters.
+    fn add_attribution(
         env: &Env,
-        caller: &Address,
         username: &String,
+        payer: &Address,
+        attributed: &Option<Address>,
         asset: &Address,
-        to: Option<Address>,
-    ) -> Result<i128, Error> {
-        let unclaimed_key = DataKey::Unclaimed(username.clone(), asset.clone());
-        let unclaimed: i128 = env
+        amount: i128,
+    ) {
+        match attributed {
+            None => {
+                storage_add(
+                    env,
+                    &DataKey::DonorToUser(DonorKey {
+                        donor: payer.clone(),
+                        username: username.clone(),
+                        asset: asset.clone(),
+                    }),
+                    amount,
+                );
+                storage_add(env, &DataKey::DonorTotal(payer.clone(), asset.clone()), amount);
+            }
+            Some(donor) => {
+                storage_add(
+                    env,
+                    &DataKey::UnverifiedDonorToUser(DonorKey {
+                        donor: donor.clone(),
+                        username: username.clone(),
+                        asset: asset.clone(),
+                    }),
+                    amount,
+                );
+                storage_add(
+                    env,
+                    &DataKey::UnverifiedDonorTotal(donor.clone(), asset.clone()),
+                    amount,
+                );
+            }
+        }
+        storage_add(env, &DataKey::GrandTotal(asset.clone()), amount);
+    }
+
+    fn escrow_donation(
+        env: &Env,
+        username: &String,
+        payer: &Address,
+        attributed: &Option<Address>,
+        asset: &Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        let escrow_key = DataKey::Escrow(DonorKey {
+            donor: payer.clone(),
+            username: username.clone(),
+            asset: asset.clone(),
+        });
+        // A payer's pending deposit carries a single attribution, so that a
+        // refund can reverse exactly what was credited.
+        let previous: i128 = match env.storage().persistent().get(&escrow_key) {
+            Some(EscrowDeposit { attributed: ref previous, .. }) if previous != attributed => {
+                return Err(Error::AttributionMismatch);
+            }
+            Some(deposit) => deposit.amount,
+            None => 0,
+        };
+        env.storage().persistent().set(
+            &escrow_key,
+            &EscrowDeposit {
+                amount: previous + amount,
+                ledger: env.ledger().sequence(),
+                attributed: attributed.clone(),
+            },
+        );
+        env.storage()
+            .persistent()
+            .extend_ttl(&escrow_key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+
+        storage_add(
+            env,
+            &DataKey::EscrowTotal(username.clone(), asset.clone()),
+            amount,
+        );
+        Self::add_attribution(env, username, payer, attributed, asset, amount);
+
+        let donor = attributed.clone().unwrap_or(payer.clone());
+        emit_escrowed(env, username, &donor, asset, amount, attributed.is_none());
+        Ok(())
+    }
+
+    /// Keeps a donation refundable while the owner has a refund window
+    /// configured. It is added to the donor's record if that is still
+    /// refundable, so a later donation doesn't take an earlier one's refund
+    /// away; otherwise it starts a new record.
+    fn record_refundable(
+        env: &Env,
+        username: &String,
+        payer: &Address,
+        asset: &Address,
+        donation: RefundableDonation,
+    ) {
+        let window: u32 = env
             .storage()
             .persistent()
-            .get(&unclaimed_key)
+            .get(&DataKey::RefundWindow(username.clone()))
             .unwrap_or(0);
+        if window == 0 {
+            return;
+        }
 
-        if unclaimed == 0 {
-            return Err(Error::NothingToDistribute);
+        let key = DataKey::LastDonation(DonorKey {
+            donor: payer.clone(),
+            username: username.clone(),
+            asset: asset.clone(),
+        });
+        let mut record = donation.clone();
+        let previous: Option<RefundableDonation> = env.storage().persistent().get(&key);
+        if let Some(previous) = previous {
+            if previous.epoch == donation.epoch
+                && donation.ledger <= previous.ledger.saturating_add(window)
+            {
+                record = previous;
+                record.amount += donation.amount;
+                record.ledger = donation.ledger;
+                add_amounts(&mut record.rounds, &donation.rounds);
+                add_amounts(&mut record.attributed, &donation.attributed);
+                add_amounts(&mut record.depths, &donation.depths);
+            }
         }
+        env.storage().persistent().set(&key, &record);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
 
-        let recipient = to.unwrap_or(caller.clone());
+    /// Adds `depths` to the hop-limited amounts stored under `key`, dropping
+    /// entries that reach zero.
+    fn add_depths(env: &Env, key: &DataKey, depths: &Map<u32, i128>) {
+        if depths.is_empty() {
+            return;
+        }
+        let mut stored: Map<u32, i128> = env
+            .storage()
+            .persistent()
+            .get(key)
+            .unwrap_or(Map::new(env));
+        for (hops, value) in depths.iter() {
+            stored.set(hops, stored.get(hops).unwrap_or(0) + value);
+        }
+        Self::save_depths(env, key, &stored);
+    }
 
-        token::Client::new(env, asset).transfer(
-            &env.current_contract_address(),
-            &recipient,
-            &unclaimed,
-        );
+    fn save_depths(env: &Env, key: &DataKey, depths: &Map<u32, i128>) {
+        let mut kept: Map<u32, i128> = Map::new(env);
+        for (hops, value) in depths.iter() {
+            if value > 0 {
+                kept.set(hops, value);
+            }
+        }
+        if kept.is_empty() {
+            env.storage().persistent().remove(key);
+            return;
+        }
+        env.storage().persistent().set(key, &kept);
+        env.storage()
+            .persistent()
+            .extend_ttl(key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
 
-        storage_add(env, &DataKey::PaidTo(recipient.clone(), asset.clone()), unclaimed);
-        env.storage().persistent().set(&unclaimed_key, &0i128);
+    fn take_owed_depths(
+        env: &Env,
+        upstream: &String,
+        downstream: &String,
+        asset: &Address,
+    ) -> Map<u32, i128> {
+        let key = DataKey::OwedDepth(EdgeKey {
+            upstream: upstream.clone(),
+            downstream: downstream.clone(),
+            asset: asset.clone(),
+        });
+        let depths: Map<u32, i128> = env
+            .storage()
+            .persistent()
+            .get(&key)
+            .unwrap_or(Map::new(env));
+        env.storage().persistent().remove(&key);
+        depths
+    }
 
-        emit_claimed(env, username, &recipient, asset, unclaimed);
-        Ok(unclaimed)
+    /// Splits `amount` taken from a pool into the hop-limited funds it
+    /// consumes, fewest hops left first, and what remains tagged in the pool.
+    /// Whatever `amount` exceeds the tagged funds by is unlimited.
+    fn draw_depths(
+        env: &Env,
+        depths: &Map<u32, i128>,
+        amount: i128,
+    ) -> (Map<u32, i128>, Map<u32, i128>) {
+        let mut drawn: Map<u32, i128> = Map::new(env);
+        let mut rest: Map<u32, i128> = Map::new(env);
+        let mut left = amount;
+        for (hops, value) in depths.iter() {
+            let take = value.min(left);
+            if take > 0 {
+                drawn.set(hops, take);
+            }
+            if value > take {
+                rest.set(hops, value - take);
+            }
+            left -= take;
+        }
+        (drawn, rest)
+    }
+
+    /// Hop-limited part of a `share` forwarded out of a distribution whose
+    /// forwardable funds `base` include `drawn`, moved down one hop. Rounds
+    /// in favour of the limit.
+    fn tag_share(env: &Env, drawn: &Map<u32, i128>, base: i128, share: i128) -> Map<u32, i128> {
+        let mut depths: Map<u32, i128> = Map::new(env);
+        if share <= 0 || base <= 0 {
+            return depths;
+        }
+        let mut tagged: i128 = 0;
+        for (hops, value) in drawn.iter() {
+            if hops == 0 {
+                continue;
+            }
+            let part = I256::from_i128(env, share)
+                .mul(&I256::from_i128(env, value))
+                .add(&I256::from_i128(env, base - 1))
+                .div(&I256::from_i128(env, base))
+                .to_i128()
+                .unwrap()
+                .min(share - tagged);
+            if part > 0 {
+                depths.set(hops - 1, part);
+                tagged += part;
+            }
+        }
+        depths
+    }
+
+    /// Records that `username` carries shares over in `asset`, so
+    /// `set_rules` knows where to look for them. Shares are only carried
+    /// over in assets the owner set a threshold for, which keeps this short.
+    fn track_owed_asset(env: &Env, username: &String, asset: &Address) {
+        let key = DataKey::OwedAssets(username.clone());
+        let mut assets: Vec<Address> = env
+            .storage()
+            .persistent()
+            .get(&key)
+            .unwrap_or(Vec::new(env));
+        if !assets.contains(asset) {
+            assets.push_back(asset.clone());
+            env.storage().persistent().set(&key, &assets);
+        }
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    /// Number of times ownership of the username has been transferred.
+    fn owner_epoch(env: &Env, username: &String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::OwnerEpoch(username.clone()))
+            .unwrap_or(0)
+    }
+
+    fn pool_epoch(env: &Env, username: &String, asset: &Address) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PoolEpoch(username.clone(), asset.clone()))
+            .unwrap_or(0)
+    }
+
+    fn bump_pool_epoch(env: &Env, username: &String, asset: &Address) {
+        let key = DataKey::PoolEpoch(username.clone(), asset.clone());
+        let epoch = Self::pool_epoch(env, username, asset).wrapping_add(1);
+        env.storage().persistent().set(&key, &epoch);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    /// Counts a donation towards every open round the username is eligible
+    /// in for this asset. Returns the ids of the rounds it was counted in.
+    fn record_round_contribution(
+        env: &Env,
+        username: &String,
+        donor: &Address,
+        asset: &Address,
+        amount: i128,
+    ) -> Map<u32, i128> {
+        let mut counted: Map<u32, i128> = Map::new(env);
+        let rounds: Vec<u32> = match env
+            .storage()
+            .persistent()
+            .get(&DataKey::UserRounds(username.clone()))
+        {
+            Some(rounds) => rounds,
+            None => return counted,
+        };
+        let now = env.ledger().sequence();
+
+        for round_id in rounds.iter() {
+            let round: Round = env
+                .storage()
+                .persistent()
+                .get(&DataKey::Round(round_id))
+                .unwrap();
+            if round.asset != *asset || now < round.start_ledger || now > round.end_ledger {
+                continue;
+            }
+
+            Self::add_round_contribution(env, round_id, username, donor, amount);
+            counted.set(round_id, amount);
+        }
+        counted
+    }
+
+    /// The rounds the user joined that have not ended yet. Ended rounds no
+    /// longer count donations, so they are dropped when a round is joined.
+    fn open_rounds(env: &Env, username: &String) -> Vec<u32> {
+        let rounds: Vec<u32> = env
+            .storage()
+            .persistent()
+            .get(&DataKey::UserRounds(username.clone()))
+            .unwrap_or(Vec::new(env));
+        let now = env.ledger().sequence();
+
+        let mut open: Vec<u32> = Vec::new(env);
+        for round_id in rounds.iter() {
+            let round: Option<Round> = env.storage().persistent().get(&DataKey::Round(round_id));
+            if round.is_some_and(|round| round.end_ledger >= now) {
+                open.push_back(round_id);
+            }
+        }
+        open
+    }
+
+    /// Adjusts a donor's round contribution by `amount` (negative to reverse
+    /// it), keeping the per-username sum of square roots of donor
+    /// contributions up to date for `finalize_round`.
+    fn add_round_contribution(
+        env: &Env,
+        round_id: u32,
+        username: &String,
+        donor: &Address,
+        amount: i128,
+    ) {
+        let key = DataKey::RoundContribution(RoundDonorKey {
+            round_id,
+            username: username.clone(),
+            donor: donor.clone(),
+        });
+        let before: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+        let after = before + amount;
+        storage_add(env, &key, amount);
+        storage_add(env, &DataKey::RoundTotal(round_id, username.clone()), amount);
+        storage_add(
+            env,
+            &DataKey::RoundSqrtSum(round_id, username.clone()),
+            isqrt(after) - isqrt(before),
+        );
+    }
+
+    fn save_round(env: &Env, round_id: u32, round: &Round) {
+        let key = DataKey::Round(round_id);
+        env.storage().persistent().set(&key, round);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
     }
 
     fn assert_owner(env: &Env, caller: &Address, username: &String) -> Result<(), Error> {
@@ -388,9 +3452,90 @@ impl CascadingDonations {
         if owner != *caller {
             return Err(Error::NotOwner);
         }
+        Self::touch_last_claim(env, username);
         Ok(())
     }
 
+    /// Restarts the expiry timer. Called whenever the owner shows they still
+    /// hold their key or the balance is being used.
+    fn touch_last_claim(env: &Env, username: &String) {
+        let key = DataKey::LastClaim(username.clone());
+        env.storage().persistent().set(&key, &env.ledger().sequence());
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    fn save_payroll(env: &Env, payroll_id: u32, entry: &PayrollEntry) {
+        let key = DataKey::Payroll(payroll_id);
+        env.storage().persistent().set(&key, entry);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, LEDGERS_PER_YEAR);
+    }
+
+    /// Starts a new period, with nothing spent, once the current one is over.
+    fn roll_allowance(env: &Env, allowance: &mut Allowance) {
+        if let Some(period) = allowance.period {
+            let elapsed = env.ledger().sequence().saturating_sub(allowance.period_start);
+            if elapsed >= period {
+                allowance.period_start += elapsed - elapsed % period;
+                allowance.spent = 0;
+            }
+        }
+    }
+
+    /// Passes for the owner and for operators holding every bit of `scope`
+    /// in an unexpired grant. Returns whether the caller is the owner.
+    /// What stays owner-only, and why, is listed in the README (Operators).
+    fn assert_authorized(
+        env: &Env,
+        caller: &Address,
+        username: &String,
+        scope: u32,
+    ) -> Result<bool, Error> {
+        let owner: Address = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Owner(username.clone()))
+            .ok_or(Error::UserNotFound)?;
+        if owner == *caller {
+            Self::touch_last_claim(env, username);
+            return Ok(true);
+        }
+
+        let grant: OperatorGrant = env
+            .storage()
+            .persistent()
+            .get(&DataKey::Operator(username.clone(), caller.clone()))
+            .ok_or(Error::NotOwner)?;
+        let expired = grant
+            .expires_ledger
+            .is_some_and(|ledger| env.ledger().sequence() > ledger);
+        if expired || grant.scopes & scope != scope {
+            return Err(Error::NotAuthorized);
+        }
+        Ok(false)
+    }
+
+    /// Checks the caller may claim for `username` and returns who is paid
+    /// when there is no `to` and no payout config: the owner themselves, or
+    /// the owner on an operator's behalf. Operators can't pick `to`.
+    fn authorize_claim(
+        env: &Env,
+        caller: &Address,
+        username: &String,
+        to: &Option<Address>,
+    ) -> Result<Address, Error> {
+        if Self::assert_authorized(env, caller, username, SCOPE_CLAIM)? {
+            return Ok(caller.clone());
+        }
+        if to.is_some() {
+            return Err(Error::NotAuthorized);
+        }
+        Ok(Self::get_owner(env.clone(), username.clone()).unwrap())
+    }
+
     fn validate_rules(env: &Env, rules: &Map<String, u32>, own_username: &String) -> Result<(), Error> {
         if rules.len() > MAX_RULES {
             return Err(Error::TooManyRules);
@@ -423,3 +3568,28 @@ impl CascadingDonations {
         Ok(())
     }
 }
+
+/// Integer square root (floor), via Newton's method.
+fn isqrt(n: i128) -> i128 {
+    if n < 2 {
+        return n.max(0);
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// Adds each amount in `from` to the same key in `into`.
+fn add_amounts<K>(into: &mut Map<K, i128>, from: &Map<K, i128>)
+where
+    K: Clone + IntoVal<Env, Val> + TryFromVal<Env, Val>,
+{
+    for (key, amount) in from.iter() {
+        let before = into.get(key.clone()).unwrap_or(0);
+        into.set(key, before + amount);
+    }
+}
@@ -15,4 +15,27 @@ pub enum Error {
     UsernameAlreadyTaken   = 10,
     RulesNotSet            = 11,
     RecipientNotRegistered = 12,
+    RoundNotFound          = 13,
+    InvalidRound           = 14,
+    RoundNotEnded          = 15,
+    RoundAlreadyFinalized  = 16,
+    EscrowLocked           = 17,
+    NothingToRefund        = 18,
+    RefundWindowClosed     = 19,
+    AttributionMismatch    = 20,
+    HopLimitNotAllowed     = 21,
+    InvalidPayoutConfig    = 22,
+    RouterNotSet           = 23,
+    SlippageExceeded       = 24,
+    InvalidVesting         = 25,
+    ScheduleNotFound       = 26,
+    NotAuthorized          = 27,
+    InvalidScope           = 28,
+    AllowanceExceeded      = 29,
+    PayrollNotFound        = 30,
+    TooManyPayrollEntries  = 31,
+    NoExpiryPolicy         = 32,
+    NotExpired             = 33,
+    TooManyRounds          = 34,
+    NotEligible            = 35,
 }
@@ -1,5 +1,7 @@
 use soroban_sdk::{Address, Env, Map, String, Symbol};
 
+use crate::storage::{ExpiryPolicy, OperatorGrant, PayrollEntry, VestingConfig};
+
 pub fn emit_registered(env: &Env, username: &String, owner: &Address) {
     env.events().publish(
         (Symbol::new(env, "registered"), username.clone()),
@@ -26,23 +28,384 @@ pub fn emit_rules_set(env: &Env, username: &String, rules: &Map<String, u32>) {
     );
 }
 
+/// `source_asset` and `source_amount` are what the donor sent; they differ
+/// from `asset` and `amount` when the donation was converted into the
+/// recipient's preferred asset.
+#[allow(clippy::too_many_arguments)]
 pub fn emit_donated(
     env: &Env,
     username: &String,
     donor: &Address,
     asset: &Address,
     amount: i128,
+    verified: bool,
+    source_asset: &Address,
+    source_amount: i128,
 ) {
     env.events().publish(
         (Symbol::new(env, "donated"), username.clone()),
+        (
+            donor.clone(),
+            asset.clone(),
+            amount,
+            verified,
+            source_asset.clone(),
+            source_amount,
+        ),
+    );
+}
+
+pub fn emit_refund_window_set(env: &Env, username: &String, ledgers: u32) {
+    env.events().publish(
+        (Symbol::new(env, "refund_window_set"), username.clone()),
+        ledgers,
+    );
+}
+
+pub fn emit_donation_refunded(
+    env: &Env,
+    username: &String,
+    donor: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "donation_refunded"), username.clone()),
+        (donor.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_escrowed(
+    env: &Env,
+    username: &String,
+    donor: &Address,
+    asset: &Address,
+    amount: i128,
+    verified: bool,
+) {
+    env.events().publish(
+        (Symbol::new(env, "escrowed"), username.clone()),
+        (donor.clone(), asset.clone(), amount, verified),
+    );
+}
+
+pub fn emit_escrow_released(env: &Env, username: &String, asset: &Address, amount: i128) {
+    env.events().publish(
+        (Symbol::new(env, "escrow_released"), username.clone()),
+        (asset.clone(), amount),
+    );
+}
+
+pub fn emit_escrow_refunded(
+    env: &Env,
+    username: &String,
+    donor: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "escrow_refunded"), username.clone()),
         (donor.clone(), asset.clone(), amount),
     );
 }
 
-pub fn emit_distributed(env: &Env, username: &String, asset: &Address, pool_snapshot: i128) {
+pub fn emit_distributed(
+    env: &Env,
+    username: &String,
+    asset: &Address,
+    amount: i128,
+    min_distribution: i128,
+    keeper: Option<&Address>,
+    keeper_reward: i128,
+) {
     env.events().publish(
         (Symbol::new(env, "distributed"), username.clone()),
-        (asset.clone(), pool_snapshot),
+        (asset.clone(), amount, min_distribution, keeper.cloned(), keeper_reward),
+    );
+}
+
+pub fn emit_forwarded(
+    env: &Env,
+    upstream: &String,
+    downstream: &String,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "forwarded"), upstream.clone()),
+        (downstream.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_min_distribution_set(
+    env: &Env,
+    username: &String,
+    asset: &Address,
+    min_distribution: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "min_distribution_set"), username.clone()),
+        (asset.clone(), min_distribution),
+    );
+}
+
+pub fn emit_auto_distribute_set(env: &Env, username: &String, enabled: bool) {
+    env.events().publish(
+        (Symbol::new(env, "auto_distribute_set"), username.clone()),
+        enabled,
+    );
+}
+
+pub fn emit_operator_granted(
+    env: &Env,
+    username: &String,
+    operator: &Address,
+    grant: &OperatorGrant,
+) {
+    env.events().publish(
+        (Symbol::new(env, "operator_granted"), username.clone()),
+        (operator.clone(), grant.scopes, grant.expires_ledger),
+    );
+}
+
+pub fn emit_operator_revoked(env: &Env, username: &String, operator: &Address) {
+    env.events().publish(
+        (Symbol::new(env, "operator_revoked"), username.clone()),
+        operator.clone(),
+    );
+}
+
+pub fn emit_vesting_set(env: &Env, username: &String, config: &Option<VestingConfig>) {
+    env.events().publish(
+        (Symbol::new(env, "vesting_set"), username.clone()),
+        config.clone(),
+    );
+}
+
+pub fn emit_vesting_created(
+    env: &Env,
+    username: &String,
+    schedule_id: u32,
+    beneficiary: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "vesting_created"), username.clone()),
+        (schedule_id, beneficiary.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_vested_withdrawn(
+    env: &Env,
+    schedule_id: u32,
+    beneficiary: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "vested_withdrawn"), schedule_id),
+        (beneficiary.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_vesting_revoked(env: &Env, username: &String, schedule_id: u32, unvested: i128) {
+    env.events().publish(
+        (Symbol::new(env, "vesting_revoked"), username.clone()),
+        (schedule_id, unvested),
+    );
+}
+
+pub fn emit_preferred_asset_set(env: &Env, username: &String, asset: &Option<Address>) {
+    env.events().publish(
+        (Symbol::new(env, "preferred_asset_set"), username.clone()),
+        asset.clone(),
+    );
+}
+
+pub fn emit_router_set(env: &Env, username: &String, router: &Option<Address>) {
+    env.events().publish(
+        (Symbol::new(env, "router_set"), username.clone()),
+        router.clone(),
+    );
+}
+
+pub fn emit_swapped(
+    env: &Env,
+    username: &String,
+    asset_in: &Address,
+    amount_in: i128,
+    asset_out: &Address,
+    amount_out: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "swapped"), username.clone()),
+        (asset_in.clone(), amount_in, asset_out.clone(), amount_out),
+    );
+}
+
+pub fn emit_push_payout_set(env: &Env, username: &String, enabled: bool) {
+    env.events().publish(
+        (Symbol::new(env, "push_payout_set"), username.clone()),
+        enabled,
+    );
+}
+
+pub fn emit_push_failed(
+    env: &Env,
+    username: &String,
+    recipient: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "push_failed"), username.clone()),
+        (recipient.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_payout_config_set(env: &Env, username: &String, targets: &Map<Address, u32>) {
+    env.events().publish(
+        (Symbol::new(env, "payout_config_set"), username.clone()),
+        targets.clone(),
+    );
+}
+
+pub fn emit_keeper_bounty_set(env: &Env, username: &String, bps: u32) {
+    env.events().publish(
+        (Symbol::new(env, "keeper_bounty_set"), username.clone()),
+        bps,
+    );
+}
+
+pub fn emit_keeper_claimed(
+    env: &Env,
+    keeper: &Address,
+    recipient: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "keeper_claimed"), keeper.clone()),
+        (recipient.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_allowance_set(
+    env: &Env,
+    username: &String,
+    spender: &Address,
+    asset: &Address,
+    amount: i128,
+    period: Option<u32>,
+) {
+    env.events().publish(
+        (Symbol::new(env, "allowance_set"), username.clone()),
+        (spender.clone(), asset.clone(), amount, period),
+    );
+}
+
+pub fn emit_allowance_spent(
+    env: &Env,
+    username: &String,
+    spender: &Address,
+    recipient: &Address,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "allowance_spent"), username.clone()),
+        (spender.clone(), recipient.clone(), asset.clone(), amount),
+    );
+}
+
+pub fn emit_payroll_created(env: &Env, username: &String, payroll_id: u32, entry: &PayrollEntry) {
+    env.events().publish(
+        (Symbol::new(env, "payroll_created"), username.clone()),
+        (
+            payroll_id,
+            entry.recipient.clone(),
+            entry.asset.clone(),
+            entry.amount,
+            entry.interval,
+        ),
+    );
+}
+
+pub fn emit_payroll_cancelled(env: &Env, username: &String, payroll_id: u32) {
+    env.events().publish(
+        (Symbol::new(env, "payroll_cancelled"), username.clone()),
+        payroll_id,
+    );
+}
+
+pub fn emit_payroll_paid(env: &Env, username: &String, payroll_id: u32, entry: &PayrollEntry) {
+    env.events().publish(
+        (Symbol::new(env, "payroll_paid"), username.clone()),
+        (
+            payroll_id,
+            entry.recipient.clone(),
+            entry.asset.clone(),
+            entry.amount,
+        ),
+    );
+}
+
+pub fn emit_payroll_skipped(
+    env: &Env,
+    username: &String,
+    payroll_id: u32,
+    entry: &PayrollEntry,
+    unclaimed: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "payroll_skipped"), username.clone()),
+        (payroll_id, entry.asset.clone(), entry.amount, unclaimed),
+    );
+}
+
+pub fn emit_payroll_failed(env: &Env, username: &String, payroll_id: u32, entry: &PayrollEntry) {
+    env.events().publish(
+        (Symbol::new(env, "payroll_failed"), username.clone()),
+        (
+            payroll_id,
+            entry.recipient.clone(),
+            entry.asset.clone(),
+            entry.amount,
+        ),
+    );
+}
+
+pub fn emit_expiry_policy_set(env: &Env, username: &String, policy: &Option<ExpiryPolicy>) {
+    env.events().publish(
+        (Symbol::new(env, "expiry_policy_set"), username.clone()),
+        policy.clone(),
+    );
+}
+
+pub fn emit_unclaimed_expired(
+    env: &Env,
+    username: &String,
+    asset: &Address,
+    amount: i128,
+    beneficiary: &Option<Address>,
+) {
+    env.events().publish(
+        (Symbol::new(env, "unclaimed_expired"), username.clone()),
+        (asset.clone(), amount, beneficiary.clone()),
+    );
+}
+
+pub fn emit_regifted(
+    env: &Env,
+    username: &String,
+    target_username: &String,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "regifted"), username.clone()),
+        (target_username.clone(), asset.clone(), amount),
     );
 }
 
@@ -58,3 +421,43 @@ pub fn emit_claimed(
         (recipient.clone(), asset.clone(), amount),
     );
 }
+
+pub fn emit_round_created(
+    env: &Env,
+    round_id: u32,
+    creator: &Address,
+    asset: &Address,
+    matching_pot: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "round_created"), round_id),
+        (creator.clone(), asset.clone(), matching_pot),
+    );
+}
+
+pub fn emit_round_joined(env: &Env, username: &String, round_id: u32) {
+    env.events().publish(
+        (Symbol::new(env, "round_joined"), username.clone()),
+        round_id,
+    );
+}
+
+pub fn emit_round_matched(
+    env: &Env,
+    username: &String,
+    round_id: u32,
+    asset: &Address,
+    amount: i128,
+) {
+    env.events().publish(
+        (Symbol::new(env, "round_matched"), username.clone()),
+        (round_id, asset.clone(), amount),
+    );
+}
+
+pub fn emit_round_finalized(env: &Env, round_id: u32, matched: i128, returned: i128) {
+    env.events().publish(
+        (Symbol::new(env, "round_finalized"), round_id),
+        (matched, returned),
+    );
+}
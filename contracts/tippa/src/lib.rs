@@ -3,9 +3,11 @@
 mod contract;
 mod errors;
 mod events;
+mod router;
 mod storage;
 
 #[cfg(test)]
 mod test;
 
 pub use crate::contract::CascadingDonationsClient;
+pub use crate::router::{SwapRouter, SwapRouterClient};
@@ -0,0 +1,18 @@
+use soroban_sdk::{contractclient, Address, Env};
+
+/// Interface the contract expects from an AMM router. Before calling `swap`
+/// the contract approves the router for `amount_in` of `asset_in`; the
+/// router pulls it from `from` with `transfer_from` and sends the output in
+/// `asset_out` back to `from`. The contract checks the output itself rather
+/// than trusting the returned amount.
+#[contractclient(name = "SwapRouterClient")]
+pub trait SwapRouter {
+    fn swap(
+        env: Env,
+        from: Address,
+        asset_in: Address,
+        asset_out: Address,
+        amount_in: i128,
+        min_out: i128,
+    ) -> i128;
+}
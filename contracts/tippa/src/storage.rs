@@ -1,8 +1,32 @@
-use soroban_sdk::{contracttype, Address, Env, String};
+use soroban_sdk::{contracttype, Address, Env, Map, String, Vec};
 
 pub const LEDGERS_PER_YEAR: u32 = 6_307_200;
 pub const TTL_THRESHOLD: u32 = 518_400;
 pub const MAX_RULES: u32 = 10;
+pub const MAX_ROUND_RECIPIENTS: u32 = 50;
+/// Most open rounds a username can join at once. Every donation to the
+/// user loads each of them.
+pub const MAX_USER_ROUNDS: u32 = 20;
+pub const MAX_PAYOUT_TARGETS: u32 = 10;
+pub const MAX_PAYROLL_ENTRIES: u32 = 20;
+
+/// Most queue entries a single `process_queue` call will pop.
+pub const MAX_QUEUE_BATCH: u32 = 50;
+
+/// Highest keeper bounty an owner can configure: 500 BPS = 5% of the pool.
+pub const MAX_KEEPER_BOUNTY: u32 = 500;
+
+/// Ledgers (~30 days) after which a donor can take back funds escrowed for
+/// a username that still has not been registered.
+pub const ESCROW_TIMEOUT: u32 = 518_400;
+
+/// Operator permissions, combined as a bitmask in `OperatorGrant::scopes`.
+/// `SCOPE_CLAIM` only ever pays the payout config or the owner.
+pub const SCOPE_CLAIM: u32 = 1;
+pub const SCOPE_SET_RULES: u32 = 2;
+/// Owner-gated distributions, always run with the owner's stored threshold.
+pub const SCOPE_DISTRIBUTE: u32 = 4;
+pub const ALL_SCOPES: u32 = SCOPE_CLAIM | SCOPE_SET_RULES | SCOPE_DISTRIBUTE;
 
 /// 10 000 BPS = 100%. Allows fractional percentages (e.g. 3050 = 30.50%).
 pub const BPS_BASE: u32 = 10_000;
@@ -15,6 +39,184 @@ pub struct DonorKey {
     pub asset:    Address,
 }
 
+#[contracttype]
+#[derive(Clone)]
+pub struct EdgeKey {
+    pub upstream:   String,
+    pub downstream: String,
+    pub asset:      Address,
+}
+
+/// One recipient's part of a distribution. `share` is their cut of this
+/// distribution and `carried_over` what was already owed to them from earlier
+/// ones. If `skipped`, the total was below the threshold and is carried over
+/// again instead of being forwarded.
+#[contracttype]
+#[derive(Clone)]
+pub struct RecipientShare {
+    pub recipient:    String,
+    pub share:        i128,
+    pub carried_over: i128,
+    pub skipped:      bool,
+}
+
+/// Breakdown of a distribution. `pool` is the pool before distributing and
+/// `amount` the part of it being distributed. `hop_limited` is the part of
+/// `owner_share` kept because its donors' hop limit ran out at this user.
+#[contracttype]
+#[derive(Clone)]
+pub struct DistributionReport {
+    pub asset:            Address,
+    pub pool:             i128,
+    pub amount:           i128,
+    pub min_distribution: i128,
+    pub keeper_reward:    i128,
+    pub shares:           Vec<RecipientShare>,
+    pub owner_share:      i128,
+    pub total_forwarded:  i128,
+    pub hop_limited:      i128,
+}
+
+/// A pool waiting in the distribution queue.
+#[contracttype]
+#[derive(Clone)]
+pub struct QueueEntry {
+    pub username: String,
+    pub asset:    Address,
+}
+
+/// Funds a donor sent to a username before it was registered. `ledger` is
+/// the ledger of the donor's latest deposit; the refund timeout runs from it.
+/// `attributed` is the self-declared donor the deposit was credited to, if
+/// it differs from the paying address.
+#[contracttype]
+#[derive(Clone)]
+pub struct EscrowDeposit {
+    pub amount:     i128,
+    pub ledger:     u32,
+    pub attributed: Option<Address>,
+}
+
+/// What a payer can still take back from a username, kept while the owner
+/// has a refund window configured. Donations add up until the pool is
+/// distributed or the window after the latest one passes. `ledger` is the
+/// latest donation's ledger and `epoch` the pool's distribution epoch; any
+/// distribution since then closes the refund. `rounds` maps each funding
+/// round to the amount counted in it, `attributed` each named donor to the
+/// amount credited to them (the rest was in the payer's own name), and
+/// `depths` each hop limit to the amount donated under it.
+#[contracttype]
+#[derive(Clone)]
+pub struct RefundableDonation {
+    pub amount:     i128,
+    pub ledger:     u32,
+    pub epoch:      u32,
+    pub rounds:     Map<u32, i128>,
+    pub attributed: Map<Address, i128>,
+    pub depths:     Map<u32, i128>,
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct RoundDonorKey {
+    pub round_id: u32,
+    pub username: String,
+    pub donor:    Address,
+}
+
+/// A quadratic funding round. `matching_pot` is held by the contract from
+/// creation until `finalize_round` splits it across `eligible` usernames.
+#[contracttype]
+#[derive(Clone)]
+pub struct Round {
+    pub creator:      Address,
+    pub asset:        Address,
+    pub matching_pot: i128,
+    pub start_ledger: u32,
+    pub end_ledger:   u32,
+    pub eligible:     Vec<String>,
+    pub finalized:    bool,
+}
+
+/// How claims vest for a user: nothing is withdrawable for `cliff` ledgers,
+/// then funds vest linearly until `duration` ledgers after the claim.
+#[contracttype]
+#[derive(Clone)]
+pub struct VestingConfig {
+    pub cliff:    u32,
+    pub duration: u32,
+}
+
+/// Claimed funds vesting to `beneficiary`. If the owner revokes the
+/// schedule, `total` is cut to the vested amount and `duration` ends there.
+#[contracttype]
+#[derive(Clone)]
+pub struct VestingSchedule {
+    pub username:     String,
+    pub asset:        Address,
+    pub beneficiary:  Address,
+    pub total:        i128,
+    pub withdrawn:    i128,
+    pub start_ledger: u32,
+    pub cliff:        u32,
+    pub duration:     u32,
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct AllowanceKey {
+    pub username: String,
+    pub spender:  Address,
+    pub asset:    Address,
+}
+
+/// What a spender may withdraw from a user's unclaimed balance. With a
+/// `period`, `spent` resets every `period` ledgers counted from
+/// `period_start`; without one, `amount` is a one-off budget. `epoch` is
+/// the username's ownership epoch when it was set: a transfer of ownership
+/// voids it.
+#[contracttype]
+#[derive(Clone)]
+pub struct Allowance {
+    pub amount:       i128,
+    pub spent:        i128,
+    pub period:       Option<u32>,
+    pub period_start: u32,
+    pub epoch:        u32,
+}
+
+/// A recurring payout of `amount` to `recipient` from the user's unclaimed
+/// balance, due every `interval` ledgers from `next_ledger` on.
+#[contracttype]
+#[derive(Clone)]
+pub struct PayrollEntry {
+    pub username:    String,
+    pub recipient:   Address,
+    pub asset:       Address,
+    pub amount:      i128,
+    pub interval:    u32,
+    pub next_ledger: u32,
+}
+
+/// What happens to a user's unclaimed balance once nothing has been claimed
+/// for `after_ledgers`: it goes to `beneficiary`, or back into the pool to
+/// cascade to dependents if there is none.
+#[contracttype]
+#[derive(Clone)]
+pub struct ExpiryPolicy {
+    pub after_ledgers: u32,
+    pub beneficiary:   Option<Address>,
+}
+
+/// Permissions an owner granted to an operator address. The grant stops
+/// working after `expires_ledger`, if set.
+#[contracttype]
+#[derive(Clone)]
+pub struct OperatorGrant {
+    pub scopes:         u32,
+    pub expires_ledger: Option<u32>,
+}
+
 #[contracttype]
 #[derive(Clone)]
 pub enum DataKey {
@@ -29,6 +231,56 @@ pub enum DataKey {
     TotalForwarded(String, Address),
     GrandTotal(Address),
     PaidTo(Address, Address),
+    RoundCount,
+    Round(u32),
+    UserRounds(String),
+    RoundContribution(RoundDonorKey),
+    RoundTotal(u32, String),
+    RoundSqrtSum(u32, String),
+    Escrow(DonorKey),
+    EscrowTotal(String, Address),
+    RefundWindow(String),
+    PoolEpoch(String, Address),
+    LastDonation(DonorKey),
+    UnverifiedDonorToUser(DonorKey),
+    UnverifiedDonorTotal(Address, Address),
+    AssetCount(String),
+    Asset(String, u32),
+    HasAsset(String, Address),
+    KeeperBounty(String),
+    KeeperRewards(Address, Address),
+    MinDistribution(String, Address),
+    AutoDistribute(String),
+    Owed(EdgeKey),
+    QueueHead,
+    QueueTail,
+    QueueItem(u32),
+    Queued(String, Address),
+    DepthPool(String, Address),
+    OwedDepth(EdgeKey),
+    PayoutConfig(String),
+    PushPayout(String),
+    Router(String),
+    PreferredAsset(String),
+    VestingConfig(String),
+    VestingCount,
+    Vesting(u32),
+    BeneficiaryVestingCount(Address),
+    BeneficiaryVesting(Address, u32),
+    UserVestingCount(String),
+    UserVesting(String, u32),
+    Operator(String, Address),
+    Operators(String),
+    Regifted(EdgeKey),
+    TotalRegifted(String, Address),
+    Allowance(AllowanceKey),
+    OwnerEpoch(String),
+    PayrollCount,
+    Payroll(u32),
+    UserPayroll(String),
+    ExpiryPolicy(String),
+    LastClaim(String),
+    OwedAssets(String),
 }
 
 pub fn storage_add(env: &Env, key: &DataKey, amount: i128) {
@@ -1,12 +1,71 @@
 #![cfg(test)]
 
 use crate::contract::{CascadingDonations, CascadingDonationsClient};
+use crate::router::SwapRouter;
+use crate::storage::{
+    DataKey, ExpiryPolicy, VestingConfig, ESCROW_TIMEOUT, SCOPE_CLAIM, SCOPE_DISTRIBUTE,
+    SCOPE_SET_RULES,
+};
 use soroban_sdk::{
-    testutils::Address as _,
+    contract, contractimpl,
+    testutils::{Address as _, Events, IssuerFlags, Ledger},
     token::{Client as TokenClient, StellarAssetClient},
-    Address, Env, Map, String,
+    vec,
+    xdr::{ContractEventBody, ScVal},
+    Address, Env, IntoVal, Map, String, Symbol, TryFromVal, Val, Vec,
 };
 
+/// Router that swaps at a fixed `rate` (output per 100 input), paying out of
+/// its own balance. Ignores `min_out` so the contract's own check is tested.
+/// `set_fill` makes it take only that percentage of the input, like a pool
+/// running out of liquidity.
+#[contract]
+pub struct MockRouter;
+
+#[contractimpl]
+impl MockRouter {
+    pub fn set_rate(env: Env, rate: i128) {
+        env.storage().instance().set(&Symbol::new(&env, "rate"), &rate);
+    }
+
+    pub fn set_fill(env: Env, percent: i128) {
+        env.storage().instance().set(&Symbol::new(&env, "fill"), &percent);
+    }
+}
+
+#[contractimpl]
+impl SwapRouter for MockRouter {
+    fn swap(
+        env: Env,
+        from: Address,
+        asset_in: Address,
+        asset_out: Address,
+        amount_in: i128,
+        _min_out: i128,
+    ) -> i128 {
+        let this = env.current_contract_address();
+        let fill: i128 = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "fill"))
+            .unwrap_or(100);
+        let amount_in = amount_in * fill / 100;
+        TokenClient::new(&env, &asset_in).transfer_from(&this, &from, &this, &amount_in);
+
+        let rate: i128 = env.storage().instance().get(&Symbol::new(&env, "rate")).unwrap();
+        let out = amount_in * rate / 100;
+        TokenClient::new(&env, &asset_out).transfer(&this, &from, &out);
+        out
+    }
+}
+
+fn new_router(env: &Env, asset_out: &Address, rate: i128) -> Address {
+    let router = env.register(MockRouter, ());
+    MockRouterClient::new(env, &router).set_rate(&rate);
+    mint(env, asset_out, &router, 1_000_000);
+    router
+}
+
 fn setup() -> (Env, Address, Address) {
     let env = Env::default();
     env.mock_all_auths();
@@ -17,6 +76,11 @@ fn setup() -> (Env, Address, Address) {
     (env, contract_id, token_id)
 }
 
+fn new_token(env: &Env) -> Address {
+    env.register_stellar_asset_contract_v2(Address::generate(env))
+        .address()
+}
+
 fn mint(env: &Env, token: &Address, to: &Address, amount: i128) {
     StellarAssetClient::new(env, token).mint(to, &amount);
 }
@@ -29,6 +93,22 @@ fn str(env: &Env, s: &str) -> String {
     String::from_str(env, s)
 }
 
+/// Whether the contract's last invocation published an event with exactly
+/// these topics and data.
+fn has_event(env: &Env, id: &Address, topics: Vec<Val>, data: Val) -> bool {
+    let data = ScVal::try_from_val(env, &data).unwrap();
+    env.events().all().filter_by_contract(id).events().iter().any(|event| {
+        let ContractEventBody::V0(body) = &event.body;
+        body.data == data
+            && body.topics.len() == topics.len() as usize
+            && body
+                .topics
+                .iter()
+                .zip(topics.iter())
+                .all(|(topic, expected)| *topic == ScVal::try_from_val(env, &expected).unwrap())
+    })
+}
+
 #[test]
 fn test_register() {
     let (env, cid, _tok) = setup();
@@ -114,7 +194,7 @@ fn test_donate() {
     c.register(&owner, &username);
     mint(&env, &tok, &donor, 1_000);
 
-    c.donate(&donor, &username, &tok, &1_000, &None);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
 
     assert_eq!(c.get_pool(&username, &tok), 1_000);
     assert_eq!(c.get_total_received(&username, &tok), 1_000);
@@ -133,7 +213,7 @@ fn test_distribute_no_rules_all_unclaimed() {
 
     c.register(&owner, &username);
     mint(&env, &tok, &donor, 1_000);
-    c.donate(&donor, &username, &tok, &1_000, &None);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
 
     c.distribute(&username, &tok, &0);
 
@@ -160,7 +240,7 @@ fn test_distribute_with_cascade() {
     c.set_rules(&owner1, &user1, &rules);
 
     mint(&env, &tok, &donor, 1_000);
-    c.donate(&donor, &user1, &tok, &1_000, &None);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
 
     c.distribute(&user1, &tok, &0);
 
@@ -183,7 +263,7 @@ fn test_claim() {
 
     c.register(&owner, &username);
     mint(&env, &tok, &donor, 1_000);
-    c.donate(&donor, &username, &tok, &1_000, &None);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
     c.distribute(&username, &tok, &0);
 
     let paid = c.claim(&owner, &username, &tok, &None);
@@ -205,9 +285,10 @@ fn test_distribute_and_claim() {
 
     c.register(&owner, &username);
     mint(&env, &tok, &donor, 500);
-    c.donate(&donor, &username, &tok, &500, &None);
+    c.donate(&donor, &username, &tok, &500, &None, &None, &0);
 
-    let paid = c.distribute_and_claim(&owner, &username, &tok, &None, &0);
+    let (report, paid) = c.distribute_and_claim(&owner, &username, &tok, &None, &0);
+    assert_eq!(report.owner_share, 500);
     assert_eq!(paid, 500);
     assert_eq!(c.get_pool(&username, &tok), 0);
     assert_eq!(c.get_unclaimed(&username, &tok), 0);
@@ -231,18 +312,2211 @@ fn test_min_distribution_skips_dust() {
     rules.set(user2.clone(), 4000u32); // 40% in BPS
     c.set_rules(&owner1, &user1, &rules);
 
+    // alice allows a dust threshold of up to 50
+    c.set_min_distribution(&owner1, &user1, &tok, &50);
+
     // Donate 100 to alice
     mint(&env, &tok, &donor, 100);
-    c.donate(&donor, &user1, &tok, &100, &None);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
 
     // Distribute with min_distribution = 50
-    // 40% of 100 = 40, which is below 50, so bob gets nothing
+    // 40% of 100 = 40, which is below 50, so bob gets nothing yet
     c.distribute(&user1, &tok, &50);
 
     // Bob's pool should be 0 (share was below threshold)
     assert_eq!(c.get_pool(&user2, &tok), 0);
-    // Owner keeps everything (100 instead of 60)
-    assert_eq!(c.get_unclaimed(&user1, &tok), 100);
+    // The skipped share is carried over for bob, not given to the owner
+    assert_eq!(c.get_owed(&user1, &user2, &tok), 40);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 60);
     // Nothing forwarded since dust was skipped
     assert_eq!(c.get_total_forwarded(&user1, &tok), 0);
 }
+
+#[test]
+fn test_carried_over_share_forwarded_once_above_threshold() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 4000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &50);
+
+    mint(&env, &tok, &donor, 200);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    c.distribute(&user1, &tok, &50);
+    assert_eq!(c.get_owed(&user1, &user2, &tok), 40);
+
+    // 40 owed + 40 new = 80, above the threshold: all of it goes out.
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    c.distribute(&user1, &tok, &50);
+
+    assert_eq!(c.get_owed(&user1, &user2, &tok), 0);
+    assert_eq!(c.get_pool(&user2, &tok), 80);
+    assert_eq!(c.get_total_forwarded(&user1, &tok), 80);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 120);
+}
+
+#[test]
+fn test_carried_over_share_paid_when_rule_removed() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 4000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &50);
+
+    mint(&env, &tok, &donor, 100);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    c.distribute(&user1, &tok, &50);
+
+    c.set_rules(&owner1, &user1, &Map::new(&env));
+
+    assert_eq!(c.get_owed(&user1, &user2, &tok), 0);
+    assert_eq!(c.get_pool(&user2, &tok), 40);
+}
+
+#[test]
+fn test_carried_over_shares_paid_in_every_asset_when_rule_removed() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+    let user3 = str(&env, "carol");
+    let tok2 = new_token(&env);
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+    c.register(&Address::generate(&env), &user3);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 4000u32);
+    rules.set(user3.clone(), 1000u32);
+    c.set_rules(&owner1, &user1, &rules);
+
+    mint(&env, &tok, &donor, 100);
+    mint(&env, &tok2, &donor, 100);
+    for asset in [&tok, &tok2] {
+        c.set_min_distribution(&owner1, &user1, asset, &50);
+        c.donate(&donor, &user1, asset, &100, &None, &None, &0);
+        c.distribute(&user1, asset, &50);
+    }
+
+    // Dropping bob pays what he is owed in both assets; carol keeps hers.
+    rules.remove(user2.clone());
+    c.set_rules(&owner1, &user1, &rules);
+    assert_eq!(c.get_pool(&user2, &tok), 40);
+    assert_eq!(c.get_pool(&user2, &tok2), 40);
+    assert_eq!(c.get_owed(&user1, &user3, &tok2), 10);
+
+    c.set_rules(&owner1, &user1, &Map::new(&env));
+    assert_eq!(c.get_pool(&user3, &tok), 10);
+    assert_eq!(c.get_pool(&user3, &tok2), 10);
+}
+
+#[test]
+fn test_quadratic_funding_round() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let funder = Address::generate(&env);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    mint(&env, &tok, &funder, 1_000);
+    let eligible = vec![&env, user1.clone(), user2.clone()];
+    let round_id = c.create_round(&funder, &tok, &1_000, &10, &20, &eligible);
+    c.join_round(&owner1, &user1, &round_id);
+    c.join_round(&owner2, &user2, &round_id);
+    assert_eq!(c.get_pool(&user1, &tok), 0);
+
+    env.ledger().set_sequence_number(10);
+
+    // alice: 4 donors x 100 -> (4 * 10)^2 - 400 = 1200
+    for _ in 0..4 {
+        let donor = Address::generate(&env);
+        mint(&env, &tok, &donor, 100);
+        c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    }
+    // bob: 1 donor x 200 (over two donations) + 1 donor x 100
+    //      -> (14 + 10)^2 - 300 = 276
+    let big_donor = Address::generate(&env);
+    mint(&env, &tok, &big_donor, 200);
+    c.donate(&big_donor, &user2, &tok, &100, &None, &None, &0);
+    c.donate(&big_donor, &user2, &tok, &100, &None, &None, &0);
+    let small_donor = Address::generate(&env);
+    mint(&env, &tok, &small_donor, 100);
+    c.donate(&small_donor, &user2, &tok, &100, &None, &None, &0);
+
+    assert_eq!(c.get_round_contribution(&round_id, &user2, &big_donor), 200);
+    assert_eq!(c.get_round_total(&round_id, &user2), 300);
+
+    env.ledger().set_sequence_number(21);
+    let matched = c.finalize_round(&round_id);
+
+    // alice: 1000 * 1200 / 1476 = 813, bob: 1000 * 276 / 1476 = 186
+    assert_eq!(c.get_pool(&user1, &tok), 400 + 813);
+    assert_eq!(c.get_pool(&user2, &tok), 300 + 186);
+    assert_eq!(matched, 999);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&funder), 1);
+    assert!(c.get_round(&round_id).unwrap().finalized);
+}
+
+#[test]
+fn test_round_ignores_donations_outside_window() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let funder = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let owner = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &funder, 500);
+    mint(&env, &tok, &donor, 300);
+
+    let eligible = vec![&env, username.clone()];
+    let round_id = c.create_round(&funder, &tok, &500, &10, &20, &eligible);
+    c.join_round(&owner, &username, &round_id);
+
+    c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+    env.ledger().set_sequence_number(21);
+    c.donate(&donor, &username, &tok, &200, &None, &None, &0);
+
+    assert_eq!(c.get_round_total(&round_id, &username), 0);
+
+    // Nothing matched, so the whole pot goes back to the funder.
+    assert_eq!(c.finalize_round(&round_id), 0);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&funder), 500);
+    assert_eq!(c.get_pool(&username, &tok), 300);
+}
+
+#[test]
+#[should_panic]
+fn test_finalize_round_before_end_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let funder = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&Address::generate(&env), &username);
+    mint(&env, &tok, &funder, 500);
+
+    let eligible = vec![&env, username.clone()];
+    let round_id = c.create_round(&funder, &tok, &500, &10, &20, &eligible);
+    env.ledger().set_sequence_number(20);
+    c.finalize_round(&round_id);
+}
+
+#[test]
+fn test_joined_rounds_per_user_are_capped() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let funder = Address::generate(&env);
+    let owner = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &funder, 100);
+
+    let eligible = vec![&env, username.clone()];
+    for _ in 0..20 {
+        let round_id = c.create_round(&funder, &tok, &1, &10, &20, &eligible);
+        c.join_round(&owner, &username, &round_id);
+    }
+    let round_id = c.create_round(&funder, &tok, &1, &10, &30, &eligible);
+    assert!(c.try_join_round(&owner, &username, &round_id).is_err());
+
+    // Rounds that have ended free their slot.
+    env.ledger().set_sequence_number(21);
+    c.join_round(&owner, &username, &round_id);
+}
+
+#[test]
+fn test_rounds_need_the_owner_to_join() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let griefer = Address::generate(&env);
+    let funder = Address::generate(&env);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &griefer, 20);
+    mint(&env, &tok, &funder, 1_000);
+    mint(&env, &tok, &donor, 100);
+
+    // Never-ending rounds naming alice take none of her slots.
+    let eligible = vec![&env, username.clone()];
+    for _ in 0..20 {
+        c.create_round(&griefer, &tok, &1, &0, &u32::MAX, &eligible);
+    }
+    assert!(c.try_join_round(&griefer, &username, &0).is_err());
+
+    let round_id = c.create_round(&funder, &tok, &1_000, &0, &100, &eligible);
+    c.join_round(&owner, &username, &round_id);
+    c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+    assert_eq!(c.get_round_total(&round_id, &username), 100);
+    assert_eq!(c.get_round_total(&0, &username), 0);
+
+    // Only eligible usernames can join.
+    let other = str(&env, "bob");
+    let other_owner = Address::generate(&env);
+    c.register(&other_owner, &other);
+    assert!(c.try_join_round(&other_owner, &other, &round_id).is_err());
+}
+
+#[test]
+fn test_donate_to_unregistered_is_escrowed() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    assert_eq!(c.get_pool(&username, &tok), 0);
+    assert_eq!(c.get_escrow(&username, &tok), 1_000);
+    assert_eq!(c.get_escrow_deposit(&donor, &username, &tok).unwrap().amount, 1_000);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &tok), 1_000);
+
+    // Once the name is registered, anyone can release the escrow into the
+    // pool.
+    assert!(c.try_release_escrow(&username, &tok).is_err());
+    c.register(&owner, &username);
+    assert_eq!(c.get_pool(&username, &tok), 0);
+    assert_eq!(c.release_escrow(&username, &tok), 1_000);
+    assert!(c.try_release_escrow(&username, &tok).is_err());
+
+    assert_eq!(c.get_escrow(&username, &tok), 0);
+    assert!(c.get_escrow_deposit(&donor, &username, &tok).is_none());
+    assert_eq!(c.get_pool(&username, &tok), 1_000);
+    assert_eq!(c.get_total_received(&username, &tok), 1_000);
+}
+
+#[test]
+fn test_junk_escrow_does_not_block_a_name() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let griefer = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let owner = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    let mut junk = vec![&env];
+    for _ in 0..20 {
+        let asset = new_token(&env);
+        mint(&env, &asset, &griefer, 1);
+        c.donate(&griefer, &username, &asset, &1, &None, &None, &0);
+        junk.push_back(asset);
+    }
+    mint(&env, &tok, &donor, 100);
+    c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+    assert_eq!(c.get_escrow(&username, &tok), 100);
+
+    // Registering and releasing the real asset ignore the junk.
+    c.register(&owner, &username);
+    assert_eq!(c.release_escrow(&username, &tok), 100);
+    assert_eq!(c.get_pool(&username, &tok), 100);
+    assert_eq!(c.get_escrow(&username, &junk.get(0).unwrap()), 1);
+}
+
+#[test]
+fn test_refund_escrow_after_timeout() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    env.ledger().set_sequence_number(ESCROW_TIMEOUT);
+    assert_eq!(c.refund_escrow(&donor, &username, &tok), 1_000);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&donor), 1_000);
+    assert_eq!(c.get_escrow(&username, &tok), 0);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &tok), 0);
+    assert_eq!(c.get_donor_total(&donor, &tok), 0);
+    assert_eq!(c.get_grand_total(&tok), 0);
+}
+
+#[test]
+#[should_panic]
+fn test_refund_escrow_before_timeout_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    env.ledger().set_sequence_number(ESCROW_TIMEOUT - 1);
+    c.refund_escrow(&donor, &username, &tok);
+}
+
+#[test]
+fn test_refund_donation_within_window() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_refund_window(&owner, &username, &100);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    env.ledger().set_sequence_number(100);
+    assert_eq!(c.refund_donation(&donor, &username, &tok), 1_000);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&donor), 1_000);
+    assert_eq!(c.get_pool(&username, &tok), 0);
+    assert_eq!(c.get_total_received(&username, &tok), 0);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &tok), 0);
+    assert_eq!(c.get_donor_total(&donor, &tok), 0);
+    assert_eq!(c.get_grand_total(&tok), 0);
+    assert!(c.get_refundable_donation(&donor, &username, &tok).is_none());
+}
+
+#[test]
+fn test_refund_covers_every_donation_in_the_window() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let named = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_refund_window(&owner, &username, &100);
+    mint(&env, &tok, &donor, 1_010);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    // A small second donation inside the window doesn't replace the first.
+    env.ledger().set_sequence_number(50);
+    c.donate(&donor, &username, &tok, &10, &Some(named.clone()), &Some(1), &0);
+    let record = c.get_refundable_donation(&donor, &username, &tok).unwrap();
+    assert_eq!(record.amount, 1_010);
+    assert_eq!(record.ledger, 50);
+
+    env.ledger().set_sequence_number(150);
+    assert_eq!(c.refund_donation(&donor, &username, &tok), 1_010);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&donor), 1_010);
+    assert_eq!(c.get_pool(&username, &tok), 0);
+    assert!(c.get_pool_depths(&username, &tok).is_empty());
+    assert_eq!(c.get_total_received(&username, &tok), 0);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &tok), 0);
+    assert_eq!(c.get_unverified_donor_to_user(&named, &username, &tok), 0);
+    assert_eq!(c.get_grand_total(&tok), 0);
+
+    // Once the window has passed, a new donation starts a fresh record.
+    mint(&env, &tok, &donor, 20);
+    c.donate(&donor, &username, &tok, &10, &None, &None, &0);
+    env.ledger().set_sequence_number(300);
+    c.donate(&donor, &username, &tok, &10, &None, &None, &0);
+    let record = c.get_refundable_donation(&donor, &username, &tok).unwrap();
+    assert_eq!(record.amount, 10);
+}
+
+#[test]
+#[should_panic]
+fn test_refund_donation_after_window_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_refund_window(&owner, &username, &100);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    env.ledger().set_sequence_number(101);
+    c.refund_donation(&donor, &username, &tok);
+}
+
+#[test]
+#[should_panic]
+fn test_refund_donation_after_distribution_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_refund_window(&owner, &username, &100);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    c.refund_donation(&donor, &username, &tok);
+}
+
+#[test]
+fn test_donate_from_relayer_allowance() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let relayer = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    TokenClient::new(&env, &tok).approve(&donor, &relayer, &600, &1_000);
+
+    c.donate_from(&relayer, &donor, &username, &tok, &600, &0);
+
+    let token_c = TokenClient::new(&env, &tok);
+    assert_eq!(token_c.balance(&donor), 400);
+    assert_eq!(token_c.allowance(&donor, &relayer), 0);
+    assert_eq!(c.get_pool(&username, &tok), 600);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &tok), 600);
+    assert_eq!(c.get_donor_to_user(&relayer, &username, &tok), 0);
+}
+
+#[test]
+fn test_donate_from_contract_allowance() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    TokenClient::new(&env, &tok).approve(&donor, &cid, &1_000, &1_000);
+
+    c.donate_from(&cid, &donor, &username, &tok, &1_000, &0);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&donor), 0);
+    assert_eq!(c.get_pool(&username, &tok), 1_000);
+    assert_eq!(c.get_donor_total(&donor, &tok), 1_000);
+}
+
+#[test]
+#[should_panic]
+fn test_donate_from_exceeding_allowance_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let relayer = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    TokenClient::new(&env, &tok).approve(&donor, &relayer, &500, &1_000);
+
+    c.donate_from(&relayer, &donor, &username, &tok, &600, &0);
+}
+
+#[test]
+fn test_donor_override_is_unverified() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let payer = Address::generate(&env);
+    let named = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &payer, 1_000);
+
+    c.donate(&payer, &username, &tok, &600, &Some(named.clone()), &None, &0);
+    // Overriding with your own address is the same as no override.
+    c.donate(&payer, &username, &tok, &400, &Some(payer.clone()), &None, &0);
+
+    assert_eq!(c.get_donor_to_user(&named, &username, &tok), 0);
+    assert_eq!(c.get_donor_total(&named, &tok), 0);
+    assert_eq!(c.get_unverified_donor_to_user(&named, &username, &tok), 600);
+    assert_eq!(c.get_unverified_donor_total(&named, &tok), 600);
+
+    assert_eq!(c.get_donor_to_user(&payer, &username, &tok), 400);
+    assert_eq!(c.get_unverified_donor_total(&payer, &tok), 0);
+    assert_eq!(c.get_grand_total(&tok), 1_000);
+}
+
+#[test]
+fn test_refund_unverified_donation_goes_to_payer() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let payer = Address::generate(&env);
+    let named = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_refund_window(&owner, &username, &100);
+    mint(&env, &tok, &payer, 1_000);
+    c.donate(&payer, &username, &tok, &1_000, &Some(named.clone()), &None, &0);
+
+    assert_eq!(c.refund_donation(&payer, &username, &tok), 1_000);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&payer), 1_000);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&named), 0);
+    assert_eq!(c.get_unverified_donor_to_user(&named, &username, &tok), 0);
+    assert_eq!(c.get_grand_total(&tok), 0);
+}
+
+#[test]
+#[should_panic]
+fn test_escrow_attribution_mismatch_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let payer = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    mint(&env, &tok, &payer, 1_000);
+    c.donate(&payer, &username, &tok, &500, &None, &None, &0);
+    c.donate(&payer, &username, &tok, &500, &Some(Address::generate(&env)), &None, &0);
+}
+
+#[test]
+fn test_asset_index_and_distribute_all() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let tok2 = new_token(&env);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5000u32);
+    c.set_rules(&owner1, &user1, &rules);
+
+    mint(&env, &tok, &donor, 1_000);
+    mint(&env, &tok2, &donor, 200);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    c.donate(&donor, &user1, &tok2, &200, &None, &None, &0);
+
+    assert_eq!(c.get_assets(&user1, &0, &10), vec![&env, tok.clone(), tok2.clone()]);
+    assert_eq!(c.get_assets(&user2, &0, &10).len(), 0);
+
+    assert_eq!(c.get_asset_count(&user1), 2);
+    assert_eq!(c.get_assets(&user1, &1, &10), vec![&env, tok2.clone()]);
+
+    let assets = c.get_assets(&user1, &0, &10);
+    assert_eq!(c.distribute_all(&user1, &assets, &0, &None), 2);
+    assert_eq!(c.get_assets(&user2, &0, &10), vec![&env, tok.clone(), tok2.clone()]);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 500);
+    assert_eq!(c.get_unclaimed(&user1, &tok2), 100);
+
+    // Everything is already distributed: empty pools are skipped, not errors.
+    assert_eq!(c.distribute_all(&user1, &assets, &0, &None), 0);
+
+    // Only the assets asked for are claimed.
+    let claimed = c.claim_all(&owner1, &user1, &vec![&env, tok2.clone()], &None);
+    assert_eq!(claimed.get(tok.clone()), None);
+    assert_eq!(claimed.get(tok2.clone()), Some(100));
+    assert_eq!(TokenClient::new(&env, &tok2).balance(&owner1), 100);
+
+    let claimed = c.claim_all(&owner1, &user1, &assets, &None);
+    assert_eq!(claimed.get(tok.clone()), Some(500));
+
+    // Nothing left to claim.
+    assert_eq!(c.claim_all(&owner1, &user1, &assets, &None).len(), 0);
+}
+
+#[test]
+fn test_keeper_bounty() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let keeper = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_keeper_bounty(&owner1, &user1, &200); // 2%
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    let quote = c.quote_distribution(&user1, &tok, &0, &Some(keeper.clone()));
+    let report = c.distribute_with_keeper(&user1, &tok, &0, &keeper);
+    assert_eq!(quote.keeper_reward, 20);
+    assert_eq!(report.keeper_reward, quote.keeper_reward);
+    assert_eq!(report.owner_share, quote.owner_share);
+
+    // 2% of 1000 to the keeper, the remaining 980 split 50/50.
+    assert_eq!(c.get_keeper_rewards(&keeper, &tok), 20);
+    assert_eq!(c.get_pool(&user2, &tok), 490);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 490);
+
+    assert_eq!(c.claim_keeper_rewards(&keeper, &tok, &None), 20);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&keeper), 20);
+    assert_eq!(c.get_keeper_rewards(&keeper, &tok), 0);
+    assert_eq!(c.get_paid_to(&keeper, &tok), 20);
+}
+
+#[test]
+fn test_keeper_bounty_not_paid_without_keeper() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_keeper_bounty(&owner, &username, &200);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    assert_eq!(c.get_unclaimed(&username, &tok), 1_000);
+}
+
+#[test]
+#[should_panic]
+fn test_keeper_bounty_above_cap_fails() {
+    let (env, cid, _tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_keeper_bounty(&owner, &username, &501);
+}
+
+#[test]
+fn test_auto_distribute_matches_manual_distribution() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let donor = Address::generate(&env);
+    let auto_owner = Address::generate(&env);
+    let manual_owner = Address::generate(&env);
+    let auto_user = str(&env, "alice");
+    let manual_user = str(&env, "carol");
+    let dep1 = str(&env, "bob");
+    let dep2 = str(&env, "dave");
+
+    c.register(&Address::generate(&env), &dep1);
+    c.register(&Address::generate(&env), &dep2);
+
+    let mut rules = Map::new(&env);
+    rules.set(dep1.clone(), 3000u32); // 300 of 1000
+    rules.set(dep2.clone(), 50u32); // 5 of 1000, below the threshold
+
+    for (owner, user) in [(&auto_owner, &auto_user), (&manual_owner, &manual_user)] {
+        c.register(owner, user);
+        c.set_rules(owner, user, &rules);
+        c.set_min_distribution(owner, user, &tok, &10);
+    }
+    c.set_auto_distribute(&auto_owner, &auto_user, &true);
+    assert!(c.get_auto_distribute(&auto_user));
+    assert!(!c.get_auto_distribute(&manual_user));
+
+    mint(&env, &tok, &donor, 2_000);
+    c.donate(&donor, &auto_user, &tok, &1_000, &None, &None, &0);
+    c.donate(&donor, &manual_user, &tok, &1_000, &None, &None, &0);
+
+    // The auto user's pool never holds funds.
+    assert_eq!(c.get_pool(&auto_user, &tok), 0);
+    assert_eq!(c.get_pool(&manual_user, &tok), 1_000);
+
+    let threshold = c.get_min_distribution(&manual_user, &tok);
+    c.distribute(&manual_user, &tok, &threshold);
+
+    assert_eq!(c.get_pool(&auto_user, &tok), c.get_pool(&manual_user, &tok));
+    assert_eq!(c.get_unclaimed(&auto_user, &tok), 695);
+    assert_eq!(c.get_unclaimed(&auto_user, &tok), c.get_unclaimed(&manual_user, &tok));
+    assert_eq!(
+        c.get_total_forwarded(&auto_user, &tok),
+        c.get_total_forwarded(&manual_user, &tok)
+    );
+    assert_eq!(
+        c.get_total_received(&auto_user, &tok),
+        c.get_total_received(&manual_user, &tok)
+    );
+    assert_eq!(c.get_pool(&dep1, &tok), 600);
+    assert_eq!(c.get_pool(&dep2, &tok), 0);
+    assert_eq!(c.get_owed(&auto_user, &dep2, &tok), 5);
+    assert_eq!(c.get_owed(&manual_user, &dep2, &tok), 5);
+}
+
+#[test]
+fn test_min_distribution_lowered_to_owner_threshold() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 4000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &10);
+
+    mint(&env, &tok, &donor, 200);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+
+    // A third party tries to push bob's 40 below the threshold: it is
+    // lowered to the owner's 10 and bob is paid.
+    let report = c.distribute(&user1, &tok, &50);
+    assert!(has_event(
+        &env,
+        &cid,
+        (Symbol::new(&env, "distributed"), user1.clone()).into_val(&env),
+        (tok.clone(), 100i128, 10i128, None::<Address>, 0i128).into_val(&env),
+    ));
+    assert_eq!(report.min_distribution, 10);
+    assert_eq!(c.get_pool(&user2, &tok), 40);
+
+    // With the default threshold of 0, any value is lowered to 0.
+    c.set_min_distribution(&owner1, &user1, &tok, &0);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    assert_eq!(c.distribute(&user1, &tok, &100).min_distribution, 0);
+    assert_eq!(c.get_pool(&user2, &tok), 80);
+}
+
+#[test]
+fn test_min_distribution_below_owner_threshold_allowed() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 4000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &50);
+
+    mint(&env, &tok, &donor, 100);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    c.distribute(&user1, &tok, &10);
+
+    assert_eq!(c.get_pool(&user2, &tok), 40);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 60);
+}
+
+#[test]
+fn test_distribute_amount_leaves_rest_of_pool() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 4000u32);
+    c.set_rules(&owner1, &user1, &rules);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+
+    c.distribute_amount(&owner1, &user1, &tok, &250, &0);
+
+    assert_eq!(c.get_pool(&user1, &tok), 750);
+    assert_eq!(c.get_pool(&user2, &tok), 100);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 150);
+    assert_eq!(c.get_total_forwarded(&user1, &tok), 100);
+
+    // The rest goes out with a regular distribution.
+    c.distribute(&user1, &tok, &0);
+
+    assert_eq!(c.get_pool(&user1, &tok), 0);
+    assert_eq!(c.get_pool(&user2, &tok), 400);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 600);
+    assert_eq!(c.get_total_forwarded(&user1, &tok), 400);
+}
+
+#[test]
+#[should_panic]
+fn test_distribute_amount_above_pool_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    c.distribute_amount(&owner, &username, &tok, &1_001, &0);
+}
+
+#[test]
+fn test_quote_distribution_matches_distribute() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+    let user3 = str(&env, "carol");
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+    c.register(&Address::generate(&env), &user3);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 3000u32); // 300
+    rules.set(user3.clone(), 20u32); // 2, below the threshold
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &10);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+
+    let quote = c.quote_distribution(&user1, &tok, &10, &None);
+
+    // Quoting does not touch state.
+    assert_eq!(c.get_pool(&user1, &tok), 1_000);
+    assert_eq!(c.get_pool(&user2, &tok), 0);
+
+    assert_eq!(quote.pool, 1_000);
+    assert_eq!(quote.amount, 1_000);
+    assert_eq!(quote.owner_share, 698);
+    assert_eq!(quote.total_forwarded, 300);
+    assert_eq!(quote.shares.len(), 2);
+
+    c.distribute(&user1, &tok, &10);
+
+    for item in quote.shares.iter() {
+        if item.skipped {
+            assert_eq!(c.get_pool(&item.recipient, &tok), 0);
+            assert_eq!(c.get_owed(&user1, &item.recipient, &tok), item.share);
+        } else {
+            assert_eq!(c.get_pool(&item.recipient, &tok), item.share + item.carried_over);
+        }
+    }
+    assert_eq!(c.get_unclaimed(&user1, &tok), quote.owner_share);
+    assert_eq!(c.get_total_forwarded(&user1, &tok), quote.total_forwarded);
+}
+
+#[test]
+fn test_process_queue_distributes_pending_pools() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5000u32);
+    c.set_rules(&owner1, &user1, &rules);
+
+    mint(&env, &tok, &donor, 2_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    // A second donation to a non-empty pool is not queued again.
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+
+    assert_eq!(c.get_queue_length(), 1);
+    let queue = c.get_queue(&0, &10);
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.get(0).unwrap().username, user1);
+
+    assert_eq!(c.process_queue(&10, &0, &None), 1);
+    assert_eq!(c.get_pool(&user1, &tok), 0);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 1_000);
+
+    // Bob's pool was filled by the distribution and is now queued.
+    assert_eq!(c.get_queue_length(), 1);
+    assert_eq!(c.get_queue(&0, &10).get(0).unwrap().username, user2);
+
+    assert_eq!(c.process_queue(&10, &0, &None), 1);
+    assert_eq!(c.get_unclaimed(&user2, &tok), 1_000);
+    assert_eq!(c.get_queue_length(), 0);
+}
+
+#[test]
+fn test_process_queue_skips_emptied_pools_and_respects_batch() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+    let user3 = str(&env, "carol");
+
+    c.register(&Address::generate(&env), &user1);
+    c.register(&Address::generate(&env), &user2);
+    c.register(&Address::generate(&env), &user3);
+
+    mint(&env, &tok, &donor, 300);
+    c.donate(&donor, &user1, &tok, &100, &None, &None, &0);
+    c.donate(&donor, &user2, &tok, &100, &None, &None, &0);
+    c.donate(&donor, &user3, &tok, &100, &None, &None, &0);
+    assert_eq!(c.get_queue_length(), 3);
+    assert_eq!(c.get_queue(&1, &1).get(0).unwrap().username, user2);
+
+    // Alice's pool is distributed directly before a keeper gets to it.
+    c.distribute(&user1, &tok, &0);
+
+    assert_eq!(c.process_queue(&2, &0, &None), 1);
+    assert_eq!(c.get_queue_length(), 1);
+    assert_eq!(c.get_pool(&user2, &tok), 0);
+    assert_eq!(c.get_pool(&user3, &tok), 100);
+
+    assert_eq!(c.process_queue(&2, &0, &None), 1);
+    assert_eq!(c.get_pool(&user3, &tok), 0);
+    assert_eq!(c.get_queue_length(), 0);
+}
+
+#[test]
+fn test_process_queue_survives_archived_items() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner, &user1);
+    c.register(&Address::generate(&env), &user2);
+
+    mint(&env, &tok, &donor, 300);
+    c.donate(&donor, &user1, &tok, &200, &None, &None, &0);
+    c.donate(&donor, &user2, &tok, &100, &None, &None, &0);
+
+    // Alice's queue entry expires from storage.
+    env.as_contract(&cid, || {
+        env.storage().persistent().remove(&DataKey::QueueItem(0));
+    });
+    assert_eq!(c.get_queue(&0, &10).len(), 1);
+    assert_eq!(c.process_queue(&10, &0, &None), 1);
+    assert_eq!(c.get_pool(&user2, &tok), 0);
+    assert_eq!(c.get_queue_length(), 0);
+
+    // A partial distribution leaves the rest of her pool queued again.
+    c.distribute_amount(&owner, &user1, &tok, &50, &0);
+    assert_eq!(c.get_queue(&0, &10).get(0).unwrap().username, user1);
+    assert_eq!(c.process_queue(&10, &0, &None), 1);
+    assert_eq!(c.get_pool(&user1, &tok), 0);
+}
+
+#[test]
+fn test_process_queue_requeues_failed_distribution() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 100);
+    c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+
+    let rules_key = DataKey::Rules(username.clone());
+    let rules: Map<String, u32> = env.as_contract(&cid, || {
+        let rules = env.storage().persistent().get(&rules_key).unwrap();
+        env.storage().persistent().remove(&rules_key);
+        rules
+    });
+    assert_eq!(c.process_queue(&10, &0, &None), 0);
+    assert_eq!(c.get_queue_length(), 1);
+    assert_eq!(c.get_pool(&username, &tok), 100);
+
+    env.as_contract(&cid, || env.storage().persistent().set(&rules_key, &rules));
+    assert_eq!(c.process_queue(&10, &0, &None), 1);
+    assert_eq!(c.get_unclaimed(&username, &tok), 100);
+}
+
+#[test]
+fn test_distribute_returns_report() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+    let user3 = str(&env, "carol");
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+    c.register(&Address::generate(&env), &user3);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 3000u32); // 300
+    rules.set(user3.clone(), 50u32); // 5, below the threshold
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &10);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+
+    let report = c.distribute(&user1, &tok, &10);
+
+    assert_eq!(report.pool, 1_000);
+    assert_eq!(report.amount, 1_000);
+    assert_eq!(report.owner_share, 695);
+    assert_eq!(report.total_forwarded, 300);
+
+    let bob = report.shares.iter().find(|s| s.recipient == user2).unwrap();
+    assert_eq!(bob.share, 300);
+    assert!(!bob.skipped);
+    let carol = report.shares.iter().find(|s| s.recipient == user3).unwrap();
+    assert_eq!(carol.share, 5);
+    assert!(carol.skipped);
+}
+
+#[test]
+fn test_max_hops_stops_cascade() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let owner2 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+    let user3 = str(&env, "carol");
+
+    c.register(&owner1, &user1);
+    c.register(&owner2, &user2);
+    c.register(&Address::generate(&env), &user3);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    let mut rules = Map::new(&env);
+    rules.set(user3.clone(), 5000u32);
+    c.set_rules(&owner2, &user2, &rules);
+
+    mint(&env, &tok, &donor, 2_000);
+    // Only alice and her direct dependencies may receive this one.
+    c.donate(&donor, &user1, &tok, &1_000, &None, &Some(1), &0);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    assert_eq!(c.get_pool_depths(&user1, &tok).get(1), Some(1_000));
+
+    c.distribute(&user1, &tok, &0);
+    assert_eq!(c.get_pool(&user2, &tok), 1_000);
+    assert_eq!(c.get_pool_depths(&user2, &tok).get(0), Some(500));
+    assert!(c.get_pool_depths(&user1, &tok).is_empty());
+
+    let report = c.distribute(&user2, &tok, &0);
+    assert_eq!(report.hop_limited, 500);
+    assert_eq!(c.get_pool(&user3, &tok), 250);
+    assert_eq!(c.get_unclaimed(&user2, &tok), 750);
+    assert!(c.get_pool_depths(&user3, &tok).is_empty());
+}
+
+#[test]
+fn test_max_hops_zero_keeps_donation_with_owner() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_refund_window(&owner1, &user1, &100);
+
+    mint(&env, &tok, &donor, 2_000);
+
+    // A refunded donation takes its hop limit with it.
+    c.donate(&donor, &user1, &tok, &1_000, &None, &Some(0), &0);
+    c.refund_donation(&donor, &user1, &tok);
+    assert!(c.get_pool_depths(&user1, &tok).is_empty());
+
+    c.donate(&donor, &user1, &tok, &1_000, &None, &Some(0), &0);
+    let report = c.distribute(&user1, &tok, &0);
+
+    assert_eq!(report.hop_limited, 1_000);
+    assert_eq!(report.total_forwarded, 0);
+    assert_eq!(c.get_pool(&user2, &tok), 0);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 1_000);
+}
+
+#[test]
+#[should_panic]
+fn test_max_hops_to_unregistered_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let donor = Address::generate(&env);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &str(&env, "ghost"), &tok, &1_000, &None, &Some(1), &0);
+}
+
+#[test]
+fn test_claim_splits_by_payout_config() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let maintainer1 = Address::generate(&env);
+    let maintainer2 = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+
+    let mut targets = Map::new(&env);
+    targets.set(maintainer1.clone(), 7000u32);
+    targets.set(maintainer2.clone(), 3000u32);
+    c.set_payout_config(&owner, &username, &targets);
+    assert_eq!(c.get_payout_config(&username), targets);
+
+    mint(&env, &tok, &donor, 1_001);
+    c.donate(&donor, &username, &tok, &1_001, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    assert_eq!(c.claim(&owner, &username, &tok, &None), 1_001);
+
+    // 700 and 300 after flooring; the payee that sorts first takes the dust.
+    let token = TokenClient::new(&env, &tok);
+    let first = c.get_payout_config(&username).keys().get(0).unwrap();
+    let (share1, share2) = if first == maintainer1 { (701, 300) } else { (700, 301) };
+    assert_eq!(token.balance(&maintainer1), share1);
+    assert_eq!(token.balance(&maintainer2), share2);
+    assert_eq!(c.get_paid_to(&maintainer1, &tok), token.balance(&maintainer1));
+    assert_eq!(c.get_paid_to(&maintainer2, &tok), token.balance(&maintainer2));
+    assert_eq!(token.balance(&owner), 0);
+
+    // An explicit `to` still overrides the config.
+    mint(&env, &tok, &donor, 100);
+    c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+    c.claim(&owner, &username, &tok, &Some(owner.clone()));
+    assert_eq!(token.balance(&owner), 100);
+}
+
+#[test]
+fn test_payout_config_cleared_on_ownership_transfer() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let new_owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let wallet = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    let mut targets = Map::new(&env);
+    targets.set(wallet.clone(), 10_000u32);
+    c.set_payout_config(&owner, &username, &targets);
+
+    c.transfer_ownership(&owner, &username, &new_owner);
+    assert!(c.get_payout_config(&username).is_empty());
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+    c.claim(&new_owner, &username, &tok, &None);
+
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&wallet), 0);
+    assert_eq!(token.balance(&new_owner), 1_000);
+}
+
+#[test]
+#[should_panic]
+fn test_payout_config_must_sum_to_full() {
+    let (env, cid, _tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+
+    let mut targets = Map::new(&env);
+    targets.set(Address::generate(&env), 6000u32);
+    c.set_payout_config(&owner, &username, &targets);
+}
+
+#[test]
+fn test_push_payout_sends_owner_share_on_distribute() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 3000u32);
+    c.set_rules(&owner1, &user1, &rules);
+    c.set_push_payout(&owner1, &user1, &true);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    c.distribute(&user1, &tok, &0);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&owner1), 700);
+    assert_eq!(c.get_paid_to(&owner1, &tok), 700);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 0);
+    assert_eq!(c.get_pool(&user2, &tok), 300);
+}
+
+#[test]
+fn test_push_payout_cleared_on_ownership_transfer() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let new_owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_push_payout(&owner, &username, &true);
+    c.transfer_ownership(&owner, &username, &new_owner);
+    assert!(!c.get_push_payout(&username));
+
+    // A third party's distribution leaves the share for the new owner to
+    // claim.
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&owner), 0);
+    assert_eq!(c.get_unclaimed(&username, &tok), 1_000);
+}
+
+#[test]
+fn test_push_payout_failure_falls_back_to_unclaimed() {
+    let (env, cid, _tok) = setup();
+    let sac = env.register_stellar_asset_contract_v2(Address::generate(&env));
+    sac.issuer().set_flag(IssuerFlags::RevocableFlag);
+    let tok = sac.address();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let blocked = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 3000u32);
+    c.set_rules(&owner1, &user1, &rules);
+
+    let mut targets = Map::new(&env);
+    targets.set(owner1.clone(), 5000u32);
+    targets.set(blocked.clone(), 5000u32);
+    c.set_payout_config(&owner1, &user1, &targets);
+    c.set_push_payout(&owner1, &user1, &true);
+
+    // The token issuer freezes one payee, so transfers to it fail.
+    StellarAssetClient::new(&env, &tok).set_authorized(&blocked, &false);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    c.distribute(&user1, &tok, &0);
+
+    assert!(has_event(
+        &env,
+        &cid,
+        (Symbol::new(&env, "forwarded"), user1.clone()).into_val(&env),
+        (user2.clone(), tok.clone(), 300i128).into_val(&env),
+    ));
+    assert!(has_event(
+        &env,
+        &cid,
+        (Symbol::new(&env, "push_failed"), user1.clone()).into_val(&env),
+        (blocked.clone(), tok.clone(), 350i128).into_val(&env),
+    ));
+    assert!(!has_event(
+        &env,
+        &cid,
+        (Symbol::new(&env, "push_failed"), user1.clone()).into_val(&env),
+        (owner1.clone(), tok.clone(), 350i128).into_val(&env),
+    ));
+
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&owner1), 350);
+    assert_eq!(token.balance(&blocked), 0);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 350);
+    // Dependents are paid regardless.
+    assert_eq!(c.get_pool(&user2, &tok), 300);
+}
+
+#[test]
+fn test_claim_as_converts_through_router() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 50);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router.clone()));
+    assert_eq!(c.get_router(&username), Some(router.clone()));
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    let out = c.claim_as(&owner, &username, &tok, &usdc, &500, &None);
+
+    assert_eq!(out, 500);
+    assert_eq!(TokenClient::new(&env, &usdc).balance(&owner), 500);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&router), 1_000);
+    assert_eq!(c.get_unclaimed(&username, &tok), 0);
+    assert_eq!(c.get_paid_to(&owner, &usdc), 500);
+}
+
+#[test]
+fn test_claim_as_keeps_unspent_input_claimable() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 50);
+    MockRouterClient::new(&env, &router).set_fill(&60);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router.clone()));
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    // The router only takes 600 of the 1000 it was approved for.
+    assert_eq!(c.claim_as(&owner, &username, &tok, &usdc, &300, &None), 300);
+    assert!(has_event(
+        &env,
+        &cid,
+        (Symbol::new(&env, "swapped"), username.clone()).into_val(&env),
+        (tok.clone(), 600i128, usdc.clone(), 300i128).into_val(&env),
+    ));
+
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&router), 600);
+    assert_eq!(token.allowance(&cid, &router), 0);
+    assert_eq!(c.get_unclaimed(&username, &tok), 400);
+    assert_eq!(c.claim(&owner, &username, &tok, &None), 400);
+}
+
+#[test]
+#[should_panic]
+fn test_claim_as_reverts_below_min_out() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 50);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router));
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    c.claim_as(&owner, &username, &tok, &usdc, &501, &None);
+}
+
+#[test]
+fn test_donation_converted_to_preferred_asset() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 200);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router));
+    c.set_preferred_asset(&owner, &username, &Some(usdc.clone()));
+
+    mint(&env, &tok, &donor, 1_000);
+    mint(&env, &usdc, &donor, 100);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &2_000);
+    // Donations already in the preferred asset are credited as is.
+    c.donate(&donor, &username, &usdc, &100, &None, &None, &0);
+
+    assert_eq!(c.get_pool(&username, &usdc), 2_100);
+    assert_eq!(c.get_pool(&username, &tok), 0);
+    assert_eq!(c.get_total_received(&username, &usdc), 2_100);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &usdc), 2_100);
+    assert_eq!(c.get_assets(&username, &0, &10), vec![&env, usdc]);
+}
+
+#[test]
+fn test_donation_conversion_refunds_unspent_input() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 200);
+    MockRouterClient::new(&env, &router).set_fill(&75);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router.clone()));
+    c.set_preferred_asset(&owner, &username, &Some(usdc.clone()));
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &1_500);
+
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&donor), 250);
+    assert_eq!(token.balance(&cid), 0);
+    assert_eq!(token.allowance(&cid, &router), 0);
+    assert_eq!(c.get_pool(&username, &usdc), 1_500);
+}
+
+#[test]
+fn test_donate_from_converted_to_preferred_asset() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let relayer = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 200);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router));
+    c.set_preferred_asset(&owner, &username, &Some(usdc.clone()));
+
+    mint(&env, &tok, &donor, 1_000);
+    TokenClient::new(&env, &tok).approve(&donor, &relayer, &1_000, &1_000);
+
+    // 1000 converts into 2000, short of the donor's bound.
+    let result = c.try_donate_from(&relayer, &donor, &username, &tok, &1_000, &2_001);
+    assert!(result.is_err());
+    assert_eq!(TokenClient::new(&env, &tok).balance(&donor), 1_000);
+
+    c.donate_from(&relayer, &donor, &username, &tok, &1_000, &2_000);
+    assert_eq!(c.get_pool(&username, &usdc), 2_000);
+    assert_eq!(c.get_pool(&username, &tok), 0);
+    assert_eq!(c.get_donor_to_user(&donor, &username, &usdc), 2_000);
+}
+
+#[test]
+#[should_panic]
+fn test_donation_conversion_below_min_out_fails() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+    let usdc = new_token(&env);
+    let router = new_router(&env, &usdc, 200);
+
+    c.register(&owner, &username);
+    c.set_router(&owner, &username, &Some(router));
+    c.set_preferred_asset(&owner, &username, &Some(usdc));
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &2_001);
+}
+
+#[test]
+fn test_claim_vests_to_beneficiary() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let maintainer = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_vesting(
+        &owner,
+        &username,
+        &Some(VestingConfig {
+            cliff: 100,
+            duration: 1_000,
+        }),
+    );
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(1_000);
+    c.claim(&owner, &username, &tok, &Some(maintainer.clone()));
+
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&maintainer), 0);
+    let ids = c.get_beneficiary_vesting(&maintainer, &0, &10);
+    assert_eq!(ids.len(), 1);
+    let id = ids.get(0).unwrap();
+    assert_eq!(c.get_user_vesting(&username, &0, &10), ids);
+
+    // Nothing before the cliff.
+    env.ledger().set_sequence_number(1_099);
+    assert_eq!(c.get_withdrawable(&id), 0);
+
+    env.ledger().set_sequence_number(1_250);
+    assert_eq!(c.withdraw_vested(&id), 250);
+    assert_eq!(token.balance(&maintainer), 250);
+    assert_eq!(c.get_paid_to(&maintainer, &tok), 250);
+
+    env.ledger().set_sequence_number(2_000);
+    assert_eq!(c.withdraw_vested(&id), 750);
+    assert!(c.get_vesting(&id).is_none());
+    assert!(c.get_beneficiary_vesting(&maintainer, &0, &10).is_empty());
+}
+
+#[test]
+fn test_vesting_indexes_are_paged_and_pruned() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let maintainer = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_vesting(
+        &owner,
+        &username,
+        &Some(VestingConfig {
+            cliff: 0,
+            duration: 1_000,
+        }),
+    );
+
+    // Three claims, one schedule each.
+    mint(&env, &tok, &donor, 300);
+    for _ in 0..3 {
+        c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+        c.distribute(&username, &tok, &0);
+        c.claim(&owner, &username, &tok, &Some(maintainer.clone()));
+    }
+
+    let ids = c.get_user_vesting(&username, &0, &10);
+    assert_eq!(ids.len(), 3);
+    assert_eq!(c.get_beneficiary_vesting(&maintainer, &0, &10), ids);
+    assert_eq!(c.get_user_vesting(&username, &1, &1), vec![&env, ids.get(1).unwrap()]);
+    assert_eq!(c.get_beneficiary_vesting(&maintainer, &2, &5).len(), 1);
+    assert!(c.get_user_vesting(&username, &3, &5).is_empty());
+
+    // A fully withdrawn schedule is skipped; the counts don't shrink.
+    env.ledger().set_sequence_number(1_000);
+    assert_eq!(c.withdraw_vested(&ids.get(0).unwrap()), 100);
+    let remaining = vec![&env, ids.get(1).unwrap(), ids.get(2).unwrap()];
+    assert_eq!(c.get_user_vesting(&username, &0, &10), remaining);
+    assert_eq!(c.get_beneficiary_vesting(&maintainer, &0, &10), remaining);
+    assert!(c.get_user_vesting(&username, &0, &1).is_empty());
+    assert_eq!(c.get_user_vesting_count(&username), 3);
+    assert_eq!(c.get_beneficiary_vesting_count(&maintainer), 3);
+}
+
+#[test]
+fn test_revoke_vesting_returns_unvested() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let maintainer = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.set_vesting(
+        &owner,
+        &username,
+        &Some(VestingConfig {
+            cliff: 0,
+            duration: 1_000,
+        }),
+    );
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(1_000);
+    c.claim(&owner, &username, &tok, &Some(maintainer.clone()));
+    let id = c.get_user_vesting(&username, &0, &10).get(0).unwrap();
+
+    env.ledger().set_sequence_number(1_400);
+    assert_eq!(c.revoke_vesting(&owner, &id), 600);
+    assert_eq!(c.get_unclaimed(&username, &tok), 600);
+
+    // The vested part stays withdrawable, and nothing more vests.
+    env.ledger().set_sequence_number(5_000);
+    assert_eq!(c.withdraw_vested(&id), 400);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&maintainer), 400);
+}
+
+#[test]
+fn test_operator_claims_pay_the_owner() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let bot = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.grant_operator(&owner, &username, &bot, &SCOPE_CLAIM, &None);
+    assert_eq!(c.get_operators(&username), vec![&env, bot.clone()]);
+    assert_eq!(c.get_operator(&username, &bot).unwrap().scopes, SCOPE_CLAIM);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    // Operators can't redirect funds to themselves.
+    assert!(c.try_claim(&bot, &username, &tok, &Some(bot.clone())).is_err());
+
+    assert_eq!(c.claim(&bot, &username, &tok, &None), 1_000);
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&owner), 1_000);
+    assert_eq!(token.balance(&bot), 0);
+
+    // The scope does not cover anything else, not even claims through a
+    // swap, where the operator would pick the price.
+    let rules: Map<String, u32> = Map::new(&env);
+    assert!(c.try_set_rules(&bot, &username, &rules).is_err());
+    let usdc = new_token(&env);
+    c.set_router(&owner, &username, &Some(new_router(&env, &usdc, 1)));
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+    assert!(c
+        .try_claim_as(&bot, &username, &tok, &usdc, &0, &None)
+        .is_err());
+    assert_eq!(c.get_unclaimed(&username, &tok), 1_000);
+}
+
+#[test]
+fn test_operator_scopes_expiry_and_revocation() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner1 = Address::generate(&env);
+    let bot = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner1, &user1);
+    c.register(&Address::generate(&env), &user2);
+    c.grant_operator(
+        &owner1,
+        &user1,
+        &bot,
+        &(SCOPE_SET_RULES | SCOPE_DISTRIBUTE),
+        &Some(100),
+    );
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5000u32);
+    c.set_rules(&bot, &user1, &rules);
+    c.set_min_distribution(&owner1, &user1, &tok, &10);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+
+    // Operators distribute with the owner's stored threshold.
+    let report = c.distribute_amount(&bot, &user1, &tok, &500, &0);
+    assert_eq!(report.min_distribution, 10);
+    assert_eq!(c.get_pool(&user2, &tok), 250);
+
+    env.ledger().set_sequence_number(101);
+    assert!(c.try_distribute_amount(&bot, &user1, &tok, &100, &0).is_err());
+
+    env.ledger().set_sequence_number(50);
+    c.revoke_operator(&owner1, &user1, &bot);
+    assert!(c.get_operator(&user1, &bot).is_none());
+    assert!(c.get_operators(&user1).is_empty());
+    assert!(c.try_distribute_amount(&bot, &user1, &tok, &100, &0).is_err());
+}
+
+#[test]
+fn test_distribute_and_claim_needs_both_operator_scopes() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let claimer = Address::generate(&env);
+    let distributor = Address::generate(&env);
+    let bot = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    c.grant_operator(&owner, &username, &claimer, &SCOPE_CLAIM, &None);
+    c.grant_operator(&owner, &username, &distributor, &SCOPE_DISTRIBUTE, &None);
+    c.grant_operator(
+        &owner,
+        &username,
+        &bot,
+        &(SCOPE_CLAIM | SCOPE_DISTRIBUTE),
+        &None,
+    );
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+
+    assert!(c
+        .try_distribute_and_claim(&claimer, &username, &tok, &None, &0)
+        .is_err());
+    assert!(c
+        .try_distribute_and_claim(&distributor, &username, &tok, &None, &0)
+        .is_err());
+    assert_eq!(c.get_pool(&username, &tok), 1_000);
+
+    let (_, claimed) = c.distribute_and_claim(&bot, &username, &tok, &None, &0);
+    assert_eq!(claimed, 1_000);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&owner), 1_000);
+}
+
+#[test]
+fn test_regift_moves_unclaimed_into_target_pool() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let charity = str(&env, "charity");
+
+    c.register(&owner, &user1);
+    c.register(&Address::generate(&env), &charity);
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    c.distribute(&user1, &tok, &0);
+
+    c.regift(&owner, &user1, &tok, &300, &charity);
+
+    assert_eq!(c.get_unclaimed(&user1, &tok), 700);
+    assert_eq!(c.get_pool(&charity, &tok), 300);
+    assert_eq!(c.get_total_received(&charity, &tok), 300);
+    assert_eq!(c.get_regifted(&user1, &charity, &tok), 300);
+    assert_eq!(c.get_total_regifted(&user1, &tok), 300);
+    // No tokens left the contract.
+    assert_eq!(TokenClient::new(&env, &tok).balance(&cid), 1_000);
+
+    assert!(c.try_regift(&owner, &user1, &tok, &701, &charity).is_err());
+}
+
+#[test]
+fn test_allowance_renews_every_period() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let contributor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(1_000);
+    c.set_allowance(&owner, &username, &contributor, &tok, &300, &Some(100));
+
+    assert_eq!(c.spend_allowance(&contributor, &username, &tok, &200, &None), 100);
+    assert!(c
+        .try_spend_allowance(&contributor, &username, &tok, &101, &None)
+        .is_err());
+
+    env.ledger().set_sequence_number(1_150);
+    assert_eq!(c.get_remaining_allowance(&username, &contributor, &tok), 300);
+    c.spend_allowance(&contributor, &username, &tok, &300, &None);
+
+    assert_eq!(TokenClient::new(&env, &tok).balance(&contributor), 500);
+    assert_eq!(c.get_paid_to(&contributor, &tok), 500);
+    assert_eq!(c.get_unclaimed(&username, &tok), 500);
+}
+
+#[test]
+fn test_allowance_limited_by_unclaimed_balance() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let contributor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 100);
+    c.donate(&donor, &username, &tok, &100, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    c.set_allowance(&owner, &username, &contributor, &tok, &1_000, &None);
+    assert!(c
+        .try_spend_allowance(&contributor, &username, &tok, &101, &None)
+        .is_err());
+
+    // Removing the allowance revokes it.
+    c.set_allowance(&owner, &username, &contributor, &tok, &0, &None);
+    assert!(c.get_allowance(&username, &contributor, &tok).is_none());
+    assert!(c
+        .try_spend_allowance(&contributor, &username, &tok, &50, &None)
+        .is_err());
+}
+
+#[test]
+fn test_allowances_void_on_ownership_transfer() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let new_owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let contributor = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    c.set_allowance(&owner, &username, &contributor, &tok, &300, &Some(100));
+    c.spend_allowance(&contributor, &username, &tok, &100, &None);
+
+    c.transfer_ownership(&owner, &username, &new_owner);
+    assert!(c.get_allowance(&username, &contributor, &tok).is_none());
+    assert_eq!(c.get_remaining_allowance(&username, &contributor, &tok), 0);
+    assert!(c
+        .try_spend_allowance(&contributor, &username, &tok, &100, &None)
+        .is_err());
+
+    // The new owner can grant a fresh one.
+    c.set_allowance(&new_owner, &username, &contributor, &tok, &50, &None);
+    assert_eq!(c.spend_allowance(&contributor, &username, &tok, &50, &None), 0);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&contributor), 150);
+    assert_eq!(c.get_unclaimed(&username, &tok), 850);
+}
+
+#[test]
+fn test_payroll_pays_when_due_and_skips_when_short() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let dev1 = Address::generate(&env);
+    let dev2 = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(1_000);
+    let id1 = c.create_payroll(&owner, &username, &dev1, &tok, &400, &100, &None);
+    let id2 = c.create_payroll(&owner, &username, &dev2, &tok, &500, &100, &Some(1_050));
+    assert_eq!(c.get_user_payroll(&username), vec![&env, id1, id2]);
+
+    // Only the first entry is due.
+    assert_eq!(c.execute_payroll(&username), 1);
+    assert_eq!(c.execute_payroll(&username), 0);
+
+    env.ledger().set_sequence_number(1_100);
+    // 600 left: dev1's second payout fits, dev2's does not.
+    assert_eq!(c.execute_payroll(&username), 1);
+
+    let token = TokenClient::new(&env, &tok);
+    assert_eq!(token.balance(&dev1), 800);
+    assert_eq!(token.balance(&dev2), 0);
+    assert_eq!(c.get_unclaimed(&username, &tok), 200);
+    assert_eq!(c.get_payroll(&id1).unwrap().next_ledger, 1_200);
+    assert_eq!(c.get_payroll(&id2).unwrap().next_ledger, 1_050);
+
+    c.cancel_payroll(&owner, &id1);
+    assert!(c.get_payroll(&id1).is_none());
+    assert_eq!(c.get_user_payroll(&username), vec![&env, id2]);
+}
+
+#[test]
+fn test_payroll_skips_missed_periods() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let dev = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(1_000);
+    let id = c.create_payroll(&owner, &username, &dev, &tok, &100, &100, &None);
+
+    // Nobody ran the payroll for four periods: it pays once, not five times.
+    env.ledger().set_sequence_number(1_450);
+    assert_eq!(c.execute_payroll(&username), 1);
+    assert_eq!(c.execute_payroll(&username), 0);
+    assert_eq!(c.get_payroll(&id).unwrap().next_ledger, 1_500);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&dev), 100);
+}
+
+#[test]
+fn test_payroll_survives_failed_transfers_and_missing_entries() {
+    let (env, cid, _tok) = setup();
+    let sac = env.register_stellar_asset_contract_v2(Address::generate(&env));
+    sac.issuer().set_flag(IssuerFlags::RevocableFlag);
+    let tok = sac.address();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let blocked = Address::generate(&env);
+    let dev = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    let archived = c.create_payroll(&owner, &username, &dev, &tok, &100, &100, &None);
+    let frozen = c.create_payroll(&owner, &username, &blocked, &tok, &200, &100, &None);
+    let id = c.create_payroll(&owner, &username, &dev, &tok, &300, &100, &None);
+
+    env.as_contract(&cid, || {
+        env.storage().persistent().remove(&DataKey::Payroll(archived));
+    });
+    StellarAssetClient::new(&env, &tok).set_authorized(&blocked, &false);
+
+    assert_eq!(c.execute_payroll(&username), 1);
+    assert!(has_event(
+        &env,
+        &cid,
+        (Symbol::new(&env, "payroll_failed"), username.clone()).into_val(&env),
+        (frozen, blocked.clone(), tok.clone(), 200i128).into_val(&env),
+    ));
+    assert_eq!(TokenClient::new(&env, &tok).balance(&dev), 300);
+    assert_eq!(c.get_unclaimed(&username, &tok), 700);
+    // The failed entry stays due.
+    assert_eq!(c.get_payroll(&frozen).unwrap().next_ledger, 0);
+    assert_eq!(c.get_payroll(&id).unwrap().next_ledger, 100);
+}
+
+#[test]
+fn test_payroll_cleared_on_ownership_transfer() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let new_owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let dev = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    let id = c.create_payroll(&owner, &username, &dev, &tok, &100, &100, &None);
+    c.transfer_ownership(&owner, &username, &new_owner);
+
+    assert!(c.get_payroll(&id).is_none());
+    assert!(c.get_user_payroll(&username).is_empty());
+    assert_eq!(c.execute_payroll(&username), 0);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&dev), 0);
+    assert_eq!(c.get_unclaimed(&username, &tok), 1_000);
+}
+
+#[test]
+fn test_expired_unclaimed_cascades_to_dependents() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let user1 = str(&env, "alice");
+    let user2 = str(&env, "bob");
+
+    c.register(&owner, &user1);
+    c.register(&Address::generate(&env), &user2);
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &user1, &tok, &1_000, &None, &None, &0);
+    c.distribute(&user1, &tok, &0);
+
+    let mut rules = Map::new(&env);
+    rules.set(user2.clone(), 5_000);
+    c.set_rules(&owner, &user1, &rules);
+
+    env.ledger().set_sequence_number(1_000);
+    let policy = ExpiryPolicy { after_ledgers: 500, beneficiary: None };
+    c.set_expiry_policy(&owner, &user1, &Some(policy));
+    assert_eq!(c.get_last_claim(&user1), 1_000);
+
+    env.ledger().set_sequence_number(1_500);
+    assert!(c.try_expire_unclaimed(&user1, &tok).is_err());
+
+    env.ledger().set_sequence_number(1_501);
+    assert_eq!(c.expire_unclaimed(&user1, &tok), 1_000);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 0);
+    assert_eq!(c.get_pool(&user1, &tok), 1_000);
+
+    c.distribute(&user1, &tok, &0);
+    assert_eq!(c.get_pool(&user2, &tok), 500);
+    assert_eq!(c.get_unclaimed(&user1, &tok), 500);
+}
+
+#[test]
+fn test_claim_resets_expiry_and_beneficiary_receives() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let heir = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    assert!(c.try_expire_unclaimed(&username, &tok).is_err());
+
+    let policy = ExpiryPolicy { after_ledgers: 100, beneficiary: Some(heir.clone()) };
+    c.set_expiry_policy(&owner, &username, &Some(policy));
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &400, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    // Claiming shows the owner is still around and restarts the timer.
+    env.ledger().set_sequence_number(90);
+    c.claim(&owner, &username, &tok, &None);
+    assert_eq!(c.get_last_claim(&username), 90);
+
+    c.donate(&donor, &username, &tok, &600, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(150);
+    assert!(c.try_expire_unclaimed(&username, &tok).is_err());
+
+    env.ledger().set_sequence_number(191);
+    assert_eq!(c.expire_unclaimed(&username, &tok), 600);
+    assert_eq!(TokenClient::new(&env, &tok).balance(&heir), 600);
+    assert_eq!(c.get_paid_to(&heir, &tok), 600);
+    assert_eq!(c.get_unclaimed(&username, &tok), 0);
+
+    // Nothing left to expire.
+    assert!(c.try_expire_unclaimed(&username, &tok).is_err());
+}
+
+#[test]
+fn test_owner_calls_and_withdrawals_reset_expiry() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let spender = Address::generate(&env);
+    let employee = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    let policy = ExpiryPolicy { after_ledgers: 100, beneficiary: None };
+    c.set_expiry_policy(&owner, &username, &Some(policy));
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    // Any call the owner signs restarts the timer, not just claims.
+    env.ledger().set_sequence_number(90);
+    c.set_allowance(&owner, &username, &spender, &tok, &100, &None);
+    c.create_payroll(&owner, &username, &employee, &tok, &100, &1_000, &Some(270));
+    assert_eq!(c.get_last_claim(&username), 90);
+
+    // So do allowance and payroll withdrawals.
+    env.ledger().set_sequence_number(180);
+    c.spend_allowance(&spender, &username, &tok, &100, &None);
+    assert_eq!(c.get_last_claim(&username), 180);
+
+    env.ledger().set_sequence_number(270);
+    assert_eq!(c.execute_payroll(&username), 1);
+    assert_eq!(c.get_last_claim(&username), 270);
+
+    env.ledger().set_sequence_number(370);
+    assert!(c.try_expire_unclaimed(&username, &tok).is_err());
+    env.ledger().set_sequence_number(371);
+    assert_eq!(c.expire_unclaimed(&username, &tok), 800);
+}
+
+#[test]
+fn test_expiry_policy_cleared_on_ownership_transfer() {
+    let (env, cid, tok) = setup();
+    let c = client(&env, &cid);
+    let owner = Address::generate(&env);
+    let new_owner = Address::generate(&env);
+    let donor = Address::generate(&env);
+    let heir = Address::generate(&env);
+    let username = str(&env, "alice");
+
+    c.register(&owner, &username);
+    let policy = ExpiryPolicy { after_ledgers: 100, beneficiary: Some(heir.clone()) };
+    c.set_expiry_policy(&owner, &username, &Some(policy));
+    c.transfer_ownership(&owner, &username, &new_owner);
+    assert!(c.get_expiry_policy(&username).is_none());
+
+    mint(&env, &tok, &donor, 1_000);
+    c.donate(&donor, &username, &tok, &1_000, &None, &None, &0);
+    c.distribute(&username, &tok, &0);
+
+    env.ledger().set_sequence_number(1_000);
+    assert!(c.try_expire_unclaimed(&username, &tok).is_err());
+    assert_eq!(TokenClient::new(&env, &tok).balance(&heir), 0);
+    assert_eq!(c.get_unclaimed(&username, &tok), 1_000);
+}